@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use floem::{
     reactive::RwSignal,
     style::Style,
@@ -46,6 +48,8 @@ fn create_view() -> impl View {
             if show {
                 async_image(URL)
                     .placeholder(placeholder.to_vec())
+                    .progress_bar(true)
+                    .progressive(Duration::from_millis(250))
                     .style(Style::size_full)
                     .any()
             } else {
@@ -1,79 +1,397 @@
 #![allow(unused)]
 
+pub mod animation;
 #[cfg(feature = "cache")]
 pub mod cache;
+pub mod decode;
+pub mod error;
+pub mod executor;
+pub mod hash;
+pub mod layout;
+#[cfg(feature = "thread")]
+pub mod pool;
+mod progressive;
+pub mod scheduler;
+mod transition;
+
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
-use bytes::Bytes;
-use crossbeam_channel::{Receiver, Sender};
+use bytes::{Bytes, BytesMut};
+use crossbeam_channel::Sender;
 use floem::{
     ext_event::create_signal_from_channel,
     id::Id,
-    reactive::{create_effect, use_context, with_scope, RwSignal, Scope},
-    view::{View, ViewData},
-    views::img,
+    peniko::Color,
+    reactive::{create_effect, use_context, with_scope, ReadSignal, RwSignal, Scope},
+    style::Style,
+    style_class,
+    unit::PxPctAuto,
+    view::{AnyView, View, ViewData},
+    views::{empty, img_dynamic, stack, Decorators},
+};
+use image::DynamicImage;
+use reqwest::{
+    header::{
+        HeaderMap, AGE, CACHE_CONTROL, CONTENT_TYPE, DATE, ETAG, EXPIRES, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED,
+    },
+    StatusCode, Url,
+};
+
+use self::{
+    animation::{player, Animation, Playback, ShownFrame},
+    decode::{decode, decode_unblocked, Decoded, Fit},
+    error::FetchError,
+    executor::{Executor, TaskHandle},
+    hash::PlaceholderHash,
+    layout::{ImageLayout, Position},
+    progressive::decode_partial,
+    scheduler::{Priority, Scheduler, SharedPriority},
+    transition::Fade,
 };
 
 #[cfg(feature = "cache")]
-use self::cache::AsyncCache;
+use self::cache::{AsyncCache, CachePolicy};
+
+style_class!(pub AsyncImageProgressClass);
+
+/// Message sent from the fetching task to the view. Images are already decoded, see
+/// [`decode`](self::decode).
+#[derive(Clone)]
+pub enum FetchEvent {
+    Progress(Progress),
+    /// [`CacheConfig::placeholder`](cache::CacheConfig::placeholder), shown until the image
+    /// or a part of it arrives
+    Placeholder(Handoff<DynamicImage>),
+    /// Part of the image that is still downloading
+    Partial(Handoff<DynamicImage>),
+    Image(Handoff<DynamicImage>),
+    /// Every frame of an animated image, for views that are
+    /// [`animated`](AsyncImage::animated)
+    Animation(Handoff<Animation>),
+    /// Fetch failed, the view keeps showing what it had
+    Error(Arc<FetchError>),
+}
+
+/// Decoded pixels in a [`FetchEvent`]. The view takes them out to show them, so that they
+/// are neither copied on the UI thread nor kept along with the last event. Clones share the
+/// contents, and every view is sent its own.
+pub struct Handoff<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> Handoff<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Self(Arc::new(Mutex::new(Some(value))))
+    }
+
+    /// `None` if it was taken already
+    #[must_use]
+    pub fn take(&self) -> Option<T> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl<T> Clone for Handoff<T> {
+    fn clone(&self) -> Self {
+        Self(Arc::clone(&self.0))
+    }
+}
+
+/// Image in the view, and the size it's laid out with. Frames of a fade have a lower
+/// resolution, and are stretched to the size of the image they fade to.
+#[derive(Clone)]
+pub(crate) struct ShownImage {
+    pub(crate) image: Rc<DynamicImage>,
+    pub(crate) size: (u32, u32),
+}
+
+impl ShownImage {
+    pub(crate) fn new(image: Rc<DynamicImage>) -> Self {
+        let size = (image.width(), image.height());
+        Self { image, size }
+    }
+}
+
+/// Per view settings for fetching the image
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchOptions {
+    /// Send partially downloaded image to the view at most once per interval
+    pub progressive: Option<Duration>,
+    /// Downscale the decoded image to fit in this size
+    pub size: Option<(u32, u32)>,
+    pub fit: Fit,
+    /// Decode every frame of animated GIF, APNG and WebP images instead of the first one
+    pub animated: bool,
+    #[cfg(feature = "cache")]
+    pub cache_policy: CachePolicy,
+    /// The view has its own placeholder, so the cache doesn't send
+    /// [`CacheConfig::placeholder`](cache::CacheConfig::placeholder)
+    #[cfg(feature = "cache")]
+    pub placeholder: bool,
+}
+
+impl FetchOptions {
+    /// Whether images decoded with these options come out the same as with `other`
+    fn decodes_like(&self, other: &Self) -> bool {
+        self.size == other.size && self.fit == other.fit && self.animated == other.animated
+    }
+}
+
+/// Download progress of an image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes received so far
+    pub received: u64,
+    /// Size of the body if the server told it
+    pub total: Option<u64>,
+}
+
+impl Progress {
+    /// Received portion between 0.0 and 1.0, if total size is known
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn fraction(&self) -> Option<f64> {
+        match self.total {
+            Some(0) => Some(1.0),
+            Some(total) => Some((self.received as f64 / total as f64).min(1.0)),
+            None => None,
+        }
+    }
+
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.total.is_some_and(|total| self.received >= total)
+    }
+}
 
 pub struct AsyncImage {
     data: ViewData,
     cx: Scope,
 
-    url: String,
-    buffer: Bytes,
-    fetch_channel: (Sender<Bytes>, Receiver<Bytes>),
+    url: Box<dyn Fn() -> String>,
+    placeholder: Option<Rc<DynamicImage>>,
+    placeholder_hash: Option<PlaceholderHash>,
+
+    progress: RwSignal<Progress>,
+    progress_bar: bool,
+    error: RwSignal<Option<Arc<FetchError>>>,
+    options: FetchOptions,
+    priority: Option<Box<dyn Fn() -> Priority>>,
+    layout: ImageLayout,
+    fade: Option<Duration>,
+    playback: Playback,
+    #[cfg(feature = "cache")]
+    cache: CacheChoice,
+}
+
+/// Cache used by a view
+#[cfg(feature = "cache")]
+enum CacheChoice {
+    /// The one provided with floems `provide_context`, if any
+    Context,
+    Cache(AsyncCache),
+    Disabled,
+}
+
+#[cfg(feature = "cache")]
+impl CacheChoice {
+    /// Must be called within the view scope
+    fn get(self) -> Option<AsyncCache> {
+        match self {
+            CacheChoice::Context => use_context::<AsyncCache>(),
+            CacheChoice::Cache(cache) => Some(cache),
+            CacheChoice::Disabled => None,
+        }
+    }
 }
 
 impl AsyncImage {
     #[must_use]
     pub fn new(url: impl Into<String>) -> Self {
-        let id = Id::next();
-        let cx = Scope::new();
-
         let url: String = url.into();
-        let fetch_channel = crossbeam_channel::bounded(1);
+        Self::new_dynamic(move || url.clone())
+    }
+
+    /// The url function is tracked. When the url changes the image is fetched again, and with
+    /// [`AsyncImage::fade`] the image shown crossfades to the new one.
+    #[must_use]
+    pub fn new_dynamic(url: impl Fn() -> String + 'static) -> Self {
+        let id = Id::next();
+        let cx = Scope::current().create_child();
 
         Self {
             data: ViewData::new(id),
             cx,
-            url,
-            buffer: Bytes::default(),
-            fetch_channel,
+            url: Box::new(url),
+            placeholder: None,
+            placeholder_hash: None,
+            progress: cx.create_rw_signal(Progress::default()),
+            progress_bar: false,
+            error: cx.create_rw_signal(None),
+            options: FetchOptions::default(),
+            priority: None,
+            layout: ImageLayout::default(),
+            fade: None,
+            playback: Playback::new(cx),
+            #[cfg(feature = "cache")]
+            cache: CacheChoice::Context,
         }
     }
 
+    /// Show this image until the image loads. It's decoded right away.
     #[must_use]
     pub fn placeholder(mut self, bytes: impl Into<Bytes>) -> Self {
-        self.buffer = bytes.into();
+        match image::load_from_memory(&bytes.into()) {
+            Ok(image) => self.placeholder = Some(Rc::new(decode::rgba(image))),
+            Err(e) => eprintln!("Invalid placeholder: {e}"),
+        }
+        #[cfg(feature = "cache")]
+        {
+            self.options.placeholder = true;
+        }
         self
     }
-}
 
-#[cfg(not(feature = "cache"))]
-impl View for AsyncImage {
-    fn view_data(&self) -> &ViewData {
-        &self.data
+    /// Show a blurred preview decoded from a BlurHash or ThumbHash until the image loads,
+    /// instead of the placeholder
+    #[must_use]
+    pub fn placeholder_hash(mut self, hash: PlaceholderHash) -> Self {
+        self.placeholder_hash = Some(hash);
+        #[cfg(feature = "cache")]
+        {
+            self.options.placeholder = true;
+        }
+        self
     }
 
-    fn view_data_mut(&mut self) -> &mut ViewData {
-        &mut self.data
+    /// Draw a progress bar over the placeholder while the image is downloading.
+    /// Style it with [`AsyncImageProgressClass`].
+    #[must_use]
+    pub fn progress_bar(mut self, show: bool) -> Self {
+        self.progress_bar = show;
+        self
     }
 
-    fn build(self) -> floem::view::AnyWidget {
-        let cx = self.cx;
-        let url = self.url;
+    /// Render progressive JPEGs and PNGs, interlaced or not, as they download, updating at
+    /// most once per `interval`. Other formats are shown when complete.
+    #[must_use]
+    pub fn progressive(mut self, interval: Duration) -> Self {
+        self.options.progressive = Some(interval);
+        self
+    }
 
-        let buffer = cx.create_rw_signal(self.buffer);
+    /// Downscale the image to `width` x `height` pixels when decoding it in the background,
+    /// so that a thumbnail doesn't keep the full resolution image around
+    #[must_use]
+    pub fn downscale(mut self, width: u32, height: u32, fit: Fit) -> Self {
+        self.options.size = Some((width, height));
+        self.options.fit = fit;
+        self
+    }
 
-        let tx = cx.create_rw_signal(self.fetch_channel.0);
-        let rx = self.fetch_channel.1;
+    /// Fit the image into the view keeping its aspect ratio, like CSS `object-fit`, and align
+    /// it by `position`. By default the image is stretched to the view.
+    #[must_use]
+    pub fn object_fit(mut self, fit: Fit, position: Position) -> Self {
+        self.layout.fit = Some((fit, position));
+        self
+    }
 
-        with_scope(cx, || async_image_view(url, buffer, tx, rx)).build()
+    /// Give the view this aspect ratio, width divided by height, so that it takes its space
+    /// before the image arrives. The view fills the width of its parent.
+    #[must_use]
+    pub fn aspect_ratio(mut self, ratio: f32) -> Self {
+        self.layout.aspect_ratio = Some(ratio);
+        self
+    }
+
+    /// Fade from the placeholder, or the image shown before, to the new image over
+    /// `duration`. Without a placeholder the image fades in.
+    #[must_use]
+    pub fn fade(mut self, duration: Duration) -> Self {
+        self.fade = Some(duration);
+        self
+    }
+
+    /// Play animated GIF, APNG and WebP images. Their frames are decoded in the background
+    /// and shown one after another, controlled with [`AsyncImage::playback`].
+    #[must_use]
+    pub fn animated(mut self) -> Self {
+        self.options.animated = true;
+        self
+    }
+
+    /// Priority of the download while it waits for the [`Scheduler`]. The function is
+    /// tracked, so the priority can follow for example whether the view is scrolled into view.
+    #[must_use]
+    pub fn priority(mut self, priority: impl Fn() -> Priority + 'static) -> Self {
+        self.priority = Some(Box::new(priority));
+        self
+    }
+
+    /// Use this cache instead of the one provided with floems `provide_context`. Without
+    /// either the image is fetched without caching.
+    #[cfg(feature = "cache")]
+    #[must_use]
+    pub fn cache(mut self, cache: AsyncCache) -> Self {
+        self.cache = CacheChoice::Cache(cache);
+        self
+    }
+
+    /// Fetch the image without caching even if a cache is provided
+    #[cfg(feature = "cache")]
+    #[must_use]
+    pub fn no_cache(mut self) -> Self {
+        self.cache = CacheChoice::Disabled;
+        self
+    }
+
+    /// How this image uses the cache tiers
+    #[cfg(feature = "cache")]
+    #[must_use]
+    pub fn cache_policy(mut self, policy: CachePolicy) -> Self {
+        self.options.cache_policy = policy;
+        self
+    }
+
+    /// Download progress of the image
+    #[must_use]
+    pub fn progress(&self) -> ReadSignal<Progress> {
+        self.progress.read_only()
+    }
+
+    /// Set if the image couldn't be fetched
+    #[must_use]
+    pub fn error(&self) -> ReadSignal<Option<Arc<FetchError>>> {
+        self.error.read_only()
+    }
+
+    /// Play, pause and loop the animation of an [`animated`](AsyncImage::animated) image
+    #[must_use]
+    pub fn playback(&self) -> Playback {
+        self.playback
     }
 }
 
-#[cfg(feature = "cache")]
+/// Priority that follows [`AsyncImage::priority`] for as long as the view exists
+fn shared_priority(cx: Scope, priority: Option<Box<dyn Fn() -> Priority>>) -> SharedPriority {
+    let shared = SharedPriority::default();
+
+    if let Some(priority) = priority {
+        let shared = shared.clone();
+        with_scope(cx, || create_effect(move |_| shared.set(priority())));
+    }
+
+    shared
+}
+
 impl View for AsyncImage {
     fn view_data(&self) -> &ViewData {
         &self.data
@@ -87,141 +405,1145 @@ impl View for AsyncImage {
         let cx = self.cx;
         let url = self.url;
 
-        let buffer = cx.create_rw_signal(self.buffer);
+        let buffer = cx.create_rw_signal(self.placeholder.map(ShownImage::new));
+        let placeholder_hash = self.placeholder_hash;
+        let progress = self.progress;
+        let progress_bar = self.progress_bar;
+        let error = self.error;
+        let options = self.options;
+        let layout = self.layout;
+        let fade = self.fade;
+        let playback = options.animated.then_some(self.playback);
+        let priority = shared_priority(cx, self.priority);
+
+        #[cfg(feature = "cache")]
+        let cache = self.cache;
+
+        with_scope(cx, || {
+            let fetcher = fetcher(
+                options,
+                priority,
+                #[cfg(feature = "cache")]
+                cache.get(),
+            );
+
+            let view = async_image_view(
+                url,
+                fetcher,
+                layout,
+                fade,
+                playback,
+                buffer,
+                progress,
+                progress_bar,
+                error,
+            );
 
-        let tx = cx.create_rw_signal(self.fetch_channel.0);
-        let rx = self.fetch_channel.1;
+            match placeholder_hash {
+                Some(hash) => hash_placeholder(view, hash, buffer),
+                None => view,
+            }
+        })
+        .build()
+    }
+}
+
+/// Starts fetching the url for a view. Returned guard is held for as long as the view wants
+/// the result.
+type Fetcher = Box<dyn Fn(&str, &Sender<FetchEvent>) -> Option<FetchGuard>>;
 
-        with_scope(cx, || async_image_view_cache(url, buffer, tx, rx)).build()
+/// Fetches through the cache if there's one, otherwise directly. Must be called within the
+/// view scope to find the provided context.
+fn fetcher(
+    options: FetchOptions,
+    priority: SharedPriority,
+    #[cfg(feature = "cache")] cache: Option<AsyncCache>,
+) -> Fetcher {
+    #[cfg(feature = "cache")]
+    if let Some(cache) = cache {
+        return Box::new(move |url, sender| cache.url(sender, url, options, priority.clone()));
     }
+
+    let scheduler = use_context::<Scheduler>().unwrap_or_else(Scheduler::global);
+    let executor = use_context::<Executor>().unwrap_or_default();
+
+    Box::new(move |url, sender| {
+        let listeners = Listeners::new(Listener::new(sender.clone(), priority.clone(), options));
+        let task = fetch(url.to_string(), listeners, scheduler.clone(), &executor);
+        Some(FetchGuard::new(move || drop(task)))
+    })
 }
 
-#[cfg(not(feature = "cache"))]
+#[allow(clippy::too_many_arguments)]
 fn async_image_view(
-    url: String,
-    buffer: RwSignal<Bytes>,
-    tx: RwSignal<Sender<Bytes>>,
-    rx: Receiver<Bytes>,
-) -> impl View {
-    let image_signal = create_signal_from_channel(rx);
+    url: Box<dyn Fn() -> String>,
+    fetcher: Fetcher,
+    layout: ImageLayout,
+    fade: Option<Duration>,
+    playback: Option<Playback>,
+    buffer: RwSignal<Option<ShownImage>>,
+    progress: RwSignal<Progress>,
+    progress_bar: bool,
+    error: RwSignal<Option<Arc<FetchError>>>,
+) -> AnyView {
+    let cx = Scope::current();
+    let fade = fade.map(|duration| Rc::new(Fade::new(duration, buffer)));
+
+    let animation = RwSignal::new(None);
+    if let Some(playback) = playback {
+        player(animation, playback, buffer);
+    }
 
-    let image_url = RwSignal::new(url);
+    // Each url is fetched in a scope of its own, so that events still on their way from the
+    // previous url are dropped along with its channel
+    create_effect(move |fetch_cx: Option<Scope>| {
+        let url = url();
 
-    create_effect(move |_| {
-        if let Some(v) = image_signal.get() {
-            buffer.set(v);
+        if let Some(fetch_cx) = fetch_cx {
+            fetch_cx.dispose();
+            progress.set(Progress::default());
+            error.set(None);
+
+            // The animation of the previous url would keep drawing over the new one
+            animation.set(None);
+            if let Some(playback) = playback {
+                playback.restart();
+            }
         }
-    });
 
-    create_effect(move |_| {
-        fetch(image_url.get_untracked(), tx.get_untracked());
+        let fetch_cx = cx.create_child();
+        with_scope(fetch_cx, || {
+            let (tx, rx) = crossbeam_channel::bounded(1);
+
+            // Created before the fetch starts, as it's what drains the channel
+            let event_signal = create_signal_from_channel(rx);
+
+            // Dropped with the scope, which cancels the fetch or releases this view's interest
+            // in it. The sender keeps the channel open until then.
+            let guard = fetcher(&url, &tx);
+            let _ = RwSignal::new((guard, tx));
+
+            show_events(
+                event_signal,
+                fade.clone(),
+                animation,
+                buffer,
+                progress,
+                error,
+            );
+        });
+        fetch_cx
     });
 
-    img(move || buffer.get().to_vec())
+    image_view(layout, buffer, progress, progress_bar, error)
+}
+
+/// Shows the preview of `hash` until something else is in the buffer. A BlurHash is decoded
+/// again when the view is resized, so that it has the aspect ratio of the view.
+fn hash_placeholder(
+    view: AnyView,
+    hash: PlaceholderHash,
+    buffer: RwSignal<Option<ShownImage>>,
+) -> AnyView {
+    let Some(preview) = hash.preview(1.0).map(Rc::new) else {
+        eprintln!("Invalid placeholder hash: {hash:?}");
+        return view;
+    };
+    buffer.set(Some(ShownImage::new(Rc::clone(&preview))));
+
+    if !hash.follows_view() {
+        return view;
+    }
+
+    let shown = RefCell::new(preview);
+
+    view.on_resize(move |rect| {
+        let replaced = buffer.with_untracked(|b| {
+            !b.as_ref()
+                .is_some_and(|b| Rc::ptr_eq(&b.image, &shown.borrow()))
+        });
+        if replaced || rect.height() <= 0.0 {
+            return;
+        }
+
+        if let Some(preview) = hash.preview(rect.width() / rect.height()).map(Rc::new) {
+            buffer.set(Some(ShownImage::new(Rc::clone(&preview))));
+            shown.replace(preview);
+        }
+    })
 }
 
 pub fn async_image(url: impl Into<String>) -> AsyncImage {
     AsyncImage::new(url)
 }
 
-#[cfg(feature = "cache")]
-fn async_image_view_cache(
-    url: String,
-    buffer: RwSignal<Bytes>,
-    tx: RwSignal<Sender<Bytes>>,
-    rx: Receiver<Bytes>,
-) -> impl View {
-    use floem::views::Decorators;
+/// Image whose url follows a signal, see [`AsyncImage::new_dynamic`]
+pub fn async_image_dynamic(url: impl Fn() -> String + 'static) -> AsyncImage {
+    AsyncImage::new_dynamic(url)
+}
 
-    let cache = use_context::<AsyncCache>().unwrap();
+/// Puts the images fetched for an url in the buffer, and reports progress and errors
+fn show_events(
+    event_signal: ReadSignal<Option<FetchEvent>>,
+    fade: Option<Rc<Fade>>,
+    animation: RwSignal<Option<Rc<[ShownFrame]>>>,
+    buffer: RwSignal<Option<ShownImage>>,
+    progress: RwSignal<Progress>,
+    error: RwSignal<Option<Arc<FetchError>>>,
+) {
+    // Returns whether the full image has arrived, so that a late partial doesn't replace it
+    create_effect(move |loaded| {
+        let loaded = loaded.unwrap_or(false);
 
-    let image_signal = create_signal_from_channel(rx);
+        match event_signal.get() {
+            Some(FetchEvent::Image(v)) => {
+                let Some(image) = v.take().map(Rc::new) else {
+                    return true;
+                };
 
-    let image_url = RwSignal::new(url);
+                if animation.with_untracked(Option::is_some) {
+                    animation.set(None);
+                }
 
-    create_effect(move |_| {
-        if let Some(v) = image_signal.get() {
-            buffer.set(v);
+                match &fade {
+                    Some(fade) => fade.start(buffer.get_untracked(), image),
+                    None => buffer.set(Some(ShownImage::new(image))),
+                }
+                true
+            }
+            Some(FetchEvent::Animation(v)) => {
+                if let Some(frames) = v.take() {
+                    animation.set(Some(frames.into_shown()));
+                }
+                true
+            }
+            Some(FetchEvent::Placeholder(v)) if !loaded => {
+                if let Some(image) = v.take() {
+                    buffer.set(Some(ShownImage::new(Rc::new(image))));
+                }
+                false
+            }
+            Some(FetchEvent::Partial(v)) if !loaded => {
+                if let Some(image) = v.take() {
+                    buffer.set(Some(ShownImage::new(Rc::new(image))));
+                }
+                false
+            }
+            Some(FetchEvent::Progress(p)) => {
+                progress.set(p);
+                loaded
+            }
+            Some(FetchEvent::Error(e)) => {
+                error.set(Some(e));
+                loaded
+            }
+            _ => loaded,
         }
     });
+}
 
-    create_effect(move |_| {
-        cache.url(&tx.get_untracked(), &image_url.get_untracked());
+fn image_view(
+    layout: ImageLayout,
+    buffer: RwSignal<Option<ShownImage>>,
+    progress: RwSignal<Progress>,
+    progress_bar: bool,
+    error: RwSignal<Option<Arc<FetchError>>>,
+) -> AnyView {
+    // Shown until there's an image, as an image view can't be empty
+    let blank = Rc::new(DynamicImage::new_rgba8(1, 1));
+    let image = img_dynamic(move || {
+        buffer.with(|b| {
+            b.as_ref()
+                .map_or_else(|| Rc::clone(&blank), |b| Rc::clone(&b.image))
+        })
     });
+    let image = layout.apply(image, buffer);
+
+    if !progress_bar {
+        return image;
+    }
+
+    let bar = empty().class(AsyncImageProgressClass).style(move |s| {
+        let progress = progress.get();
+        let fraction = progress.fraction();
+
+        s.absolute()
+            .inset_bottom(0.0)
+            .inset_left(0.0)
+            .height(4.0)
+            .width(PxPctAuto::Pct(fraction.unwrap_or_default() * 100.0))
+            .background(Color::rgb8(41, 98, 218))
+            .apply_if(
+                fraction.is_none() || progress.is_done() || error.with(Option::is_some),
+                Style::hide,
+            )
+    });
+
+    stack((image, bar)).any()
+}
+
+/// Runs the given function when dropped. Held by the view for as long as it wants the
+/// result of a fetch.
+#[must_use]
+pub struct FetchGuard(Option<Box<dyn FnOnce()>>);
+
+impl FetchGuard {
+    pub(crate) fn new(f: impl FnOnce() + 'static) -> Self {
+        Self(Some(Box::new(f)))
+    }
+}
+
+impl Drop for FetchGuard {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            f();
+        }
+    }
+}
 
-    img(move || buffer.get().to_vec())
+/// Fetches the image on the executor, sending it to `listeners`
+fn fetch(
+    url: String,
+    listeners: Listeners,
+    scheduler: Scheduler,
+    executor: &Executor,
+) -> TaskHandle {
+    executor.spawn(async move {
+        if let Err(e) = fetch_image(&url, &listeners, &scheduler).await {
+            send_error(&listeners, e);
+        }
+    })
 }
 
-#[inline]
-fn fetch(url: String, sender: Sender<Bytes>) {
-    #[cfg(feature = "tokio")]
-    fetch_tokio(url, sender);
+/// Downloads the image and sends it decoded to the views. Must be run within tokio context.
+async fn fetch_image(
+    url: &str,
+    listeners: &Listeners,
+    scheduler: &Scheduler,
+) -> Result<(), FetchError> {
+    let url = Url::parse(url).map_err(FetchError::Url)?;
+    let (bytes, _) = download(url, &Validators::default(), listeners, scheduler)
+        .await?
+        .into_modified()?;
+    listeners.send_image(bytes).await
+}
 
-    #[cfg(feature = "async-std")]
-    fetch_async_std(url, sender);
+fn send_image(sender: &Sender<FetchEvent>, image: Decoded) {
+    if let Err(e) = sender.send(image.into()) {
+        eprintln!("{e}");
+    }
+}
 
-    #[cfg(feature = "smol")]
-    fetch_async_smol(url, sender);
+/// Sends from the blocking thread pool, for callers on the UI thread. The channel of a view
+/// holds one event, and the UI thread can't wait for the view to take the previous one.
+fn send_unblocked(sender: Sender<FetchEvent>, event: FetchEvent) {
+    blocking::unblock(move || {
+        let _ = sender.send(event);
+    })
+    .detach();
+}
+
+/// Pairs each view with its own copy of the image. The last one gets the image itself.
+fn copies<T: Clone>(
+    senders: Vec<Sender<FetchEvent>>,
+    image: T,
+) -> impl Iterator<Item = (Sender<FetchEvent>, T)> {
+    let last = senders.len().saturating_sub(1);
+    let mut image = Some(image);
+
+    senders
+        .into_iter()
+        .enumerate()
+        .filter_map(move |(i, sender)| {
+            let copy = if i == last {
+                image.take()
+            } else {
+                image.clone()
+            };
+            copy.map(|copy| (sender, copy))
+        })
+}
 
-    #[cfg(feature = "thread")]
-    fetch_thread(url, sender);
+/// Logs the error and tells the views about it
+fn send_error(listeners: &Listeners, error: impl Into<Arc<FetchError>>) {
+    let error = error.into();
+    eprintln!("{error}");
+    listeners.send_error(error);
 }
 
-#[cfg(feature = "tokio")]
-fn fetch_tokio(url: String, sender: Sender<Bytes>) {
-    tokio::spawn(async move {
-        let response = reqwest::get(url).await?;
-        let bytes = response.bytes().await?;
+/// Views waiting for a fetch. With cache several views can wait for the same url, and ones
+/// that join late get the latest image right away.
+#[derive(Clone, Default)]
+pub(crate) struct Listeners(Arc<Mutex<ListenersState>>);
+
+#[derive(Default)]
+struct ListenersState {
+    listeners: Vec<Listener>,
+    /// Latest image as downloaded, or error
+    last: Option<Result<Bytes, Arc<FetchError>>>,
+}
 
-        if let Err(e) = sender.send(bytes) {
-            eprintln!("{e}");
+/// View waiting for a fetch
+pub(crate) struct Listener {
+    sender: Sender<FetchEvent>,
+    priority: SharedPriority,
+    /// Each view gets the image decoded with its own options
+    options: FetchOptions,
+}
+
+/// View that joined a fetch, along with its result if the fetch is already done
+#[must_use]
+pub(crate) struct Joined {
+    sender: Sender<FetchEvent>,
+    options: FetchOptions,
+    last: Option<Result<Bytes, Arc<FetchError>>>,
+}
+
+impl Listener {
+    pub(crate) fn new(
+        sender: Sender<FetchEvent>,
+        priority: SharedPriority,
+        options: FetchOptions,
+    ) -> Self {
+        Self {
+            sender,
+            priority,
+            options,
         }
+    }
+}
 
-        reqwest::Result::Ok(())
-    });
+impl Listeners {
+    pub(crate) fn new(listener: Listener) -> Self {
+        Self(Arc::new(Mutex::new(ListenersState {
+            listeners: vec![listener],
+            last: None,
+        })))
+    }
+
+    /// The view is sent the result it missed by [`Joined::catch_up`], which the caller runs
+    /// once it isn't holding any locks
+    pub(crate) fn add(&self, listener: Listener) -> Joined {
+        let mut state = self.0.lock().unwrap();
+
+        let joined = Joined {
+            sender: listener.sender.clone(),
+            options: listener.options,
+            last: state.last.clone(),
+        };
+        state.listeners.push(listener);
+        joined
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().listeners.is_empty()
+    }
+
+    /// Returns the number of views left
+    pub(crate) fn remove(&self, sender: &Sender<FetchEvent>) -> usize {
+        let mut state = self.0.lock().unwrap();
+
+        if let Some(i) = state
+            .listeners
+            .iter()
+            .position(|listener| listener.sender.same_channel(sender))
+        {
+            state.listeners.swap_remove(i);
+        }
+
+        state.listeners.len()
+    }
+
+    /// Highest priority of the views. Fetches nobody waits for anymore come last.
+    pub(crate) fn priority(&self) -> Priority {
+        let state = self.0.lock().unwrap();
+
+        state
+            .listeners
+            .iter()
+            .map(|listener| listener.priority.get())
+            .max()
+            .unwrap_or(Priority::Low)
+    }
+
+    /// Shortest interval between partial images that any view asked for
+    fn progressive(&self) -> Option<Duration> {
+        let state = self.0.lock().unwrap();
+
+        state
+            .listeners
+            .iter()
+            .filter_map(|listener| listener.options.progressive)
+            .min()
+    }
+
+    /// Views that want partial images, grouped like in [`Listeners::send_image`]
+    fn progressive_groups(&self) -> Vec<(FetchOptions, Vec<Sender<FetchEvent>>)> {
+        let state = self.0.lock().unwrap();
+        state.groups(|listener| listener.options.progressive.is_some())
+    }
+
+    /// Decodes the image once for each distinct [`FetchOptions`] of the views, on the blocking
+    /// thread pool, and sends every view its own. Views that join later decode it when they
+    /// join. Sending blocks until the view has room for the image, so it's done without
+    /// holding the lock.
+    pub(crate) async fn send_image(&self, bytes: Bytes) -> Result<(), FetchError> {
+        let groups = {
+            let mut state = self.0.lock().unwrap();
+            state.last = Some(Ok(bytes.clone()));
+            state.groups(|_| true)
+        };
+
+        for (options, senders) in groups {
+            let image = decode_unblocked(bytes.clone(), options).await?;
+
+            for (sender, image) in copies(senders, image) {
+                // Views that are gone have disconnected channels
+                let _ = sender.send(image.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn send_error(&self, error: Arc<FetchError>) {
+        let senders = {
+            let mut state = self.0.lock().unwrap();
+            state.last = Some(Err(Arc::clone(&error)));
+            state
+                .listeners
+                .iter()
+                .map(|listener| listener.sender.clone())
+                .collect::<Vec<_>>()
+        };
+
+        for sender in senders {
+            let _ = sender.send(FetchEvent::Error(Arc::clone(&error)));
+        }
+    }
+
+    /// For progress, see [`Body::send_progress`]
+    fn try_send(&self, event: &FetchEvent) {
+        for listener in &self.0.lock().unwrap().listeners {
+            let _ = listener.sender.try_send(event.clone());
+        }
+    }
 }
 
-#[cfg(feature = "async-std")]
-fn fetch_async_std(url: String, sender: Sender<Bytes>) {
-    async_std::task::spawn(async move {
-        if let Err(e) = fetch_compat(url, sender).await {
-            eprintln!("{e}");
+impl ListenersState {
+    /// Senders of the views matching `filter`, grouped by how they decode images
+    fn groups(
+        &self,
+        filter: impl Fn(&Listener) -> bool,
+    ) -> Vec<(FetchOptions, Vec<Sender<FetchEvent>>)> {
+        let mut groups: Vec<(FetchOptions, Vec<_>)> = Vec::new();
+
+        for listener in self.listeners.iter().filter(|listener| filter(listener)) {
+            let sender = listener.sender.clone();
+
+            match groups
+                .iter_mut()
+                .find(|(options, _)| options.decodes_like(&listener.options))
+            {
+                Some((_, senders)) => senders.push(sender),
+                None => groups.push((listener.options, vec![sender])),
+            }
         }
-    });
+
+        groups
+    }
 }
 
-#[cfg(feature = "smol")]
-fn fetch_async_smol(url: String, sender: Sender<Bytes>) {
-    smol::spawn(async move {
-        if let Err(e) = fetch_compat(url, sender).await {
-            eprintln!("{e}");
+impl Joined {
+    /// Sends the result of a finished fetch to the view. Decoding happens on the blocking
+    /// thread pool, so this can be called from the UI thread.
+    pub(crate) fn catch_up(self) {
+        let Joined {
+            sender,
+            options,
+            last,
+        } = self;
+
+        match last {
+            Some(Ok(bytes)) => blocking::unblock(move || match decode(&bytes, options) {
+                Ok(image) => send_image(&sender, image),
+                Err(e) => {
+                    let _ = sender.send(FetchEvent::Error(Arc::new(e)));
+                }
+            })
+            .detach(),
+            Some(Err(error)) => send_unblocked(sender, FetchEvent::Error(error)),
+            None => {}
         }
-    })
-    .detach();
+    }
 }
 
-#[cfg(feature = "thread")]
-fn fetch_thread(url: String, sender: Sender<Bytes>) {
-    let _ = std::thread::spawn(move || {
-        let response = reqwest::blocking::get(url)?;
-        let bytes = response.bytes()?;
-        if let Err(e) = sender.send(bytes) {
-            eprintln!("{e}");
+/// Initial capacity for the body buffer. `Content-Length` is not trusted beyond this.
+const MAX_PREALLOC: u64 = 16 * 1024 * 1024;
+
+/// Collects the response body while reporting progress and partial images to the view
+struct Body<'a> {
+    buffer: BytesMut,
+    total: Option<u64>,
+    listeners: &'a Listeners,
+    last_partial: Instant,
+    /// Set while a partial image is being decoded
+    decoding: Arc<AtomicBool>,
+}
+
+impl<'a> Body<'a> {
+    #[allow(clippy::cast_possible_truncation)]
+    fn new(total: Option<u64>, listeners: &'a Listeners) -> Self {
+        let body = Self {
+            buffer: BytesMut::with_capacity(total.unwrap_or_default().min(MAX_PREALLOC) as usize),
+            total,
+            listeners,
+            last_partial: Instant::now(),
+            decoding: Arc::new(AtomicBool::new(false)),
+        };
+        body.send_progress();
+        body
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+        self.send_progress();
+
+        if let Some(interval) = self.listeners.progressive() {
+            if self.last_partial.elapsed() >= interval {
+                self.last_partial = Instant::now();
+                self.send_partial();
+            }
         }
+    }
 
-        std::result::Result::<(), reqwest::Error>::Ok(())
-    });
+    fn finish(self) -> Result<Bytes, FetchError> {
+        let bytes = self.buffer.freeze();
+        check_image_bytes(&bytes)?;
+        Ok(bytes)
+    }
+
+    /// Progress and partial images are sent with `try_send` so that slow view doesn't
+    /// throttle the download. Dropped updates are fine as only the latest one matters.
+    fn send_progress(&self) {
+        self.listeners.try_send(&FetchEvent::Progress(Progress {
+            received: self.buffer.len() as u64,
+            total: self.total,
+        }));
+    }
+
+    /// Decodes on the blocking pool so the download isn't held up, once for each distinct
+    /// [`FetchOptions`] of the views that are progressive. Skipped if the previous partial
+    /// image is still being decoded.
+    fn send_partial(&self) {
+        if self.decoding.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        let data = self.buffer.clone().freeze();
+        let groups = self.listeners.progressive_groups();
+        let decoding = Arc::clone(&self.decoding);
+
+        blocking::unblock(move || {
+            for (options, senders) in groups {
+                if let Some(image) = decode_partial(&data, options) {
+                    for (sender, image) in copies(senders, image) {
+                        let _ = sender.try_send(FetchEvent::Partial(Handoff::new(image)));
+                    }
+                }
+            }
+            decoding.store(false, Ordering::Release);
+        })
+        .detach();
+    }
+}
+
+/// Shared by all fetches, so that connections are reused
+fn client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(reqwest::Client::new)
 }
 
-#[cfg(any(feature = "smol", feature = "async-std"))]
-async fn fetch_compat(url: String, sender: Sender<Bytes>) -> Result<(), reqwest::Error> {
-    use async_compat::CompatExt;
+/// Downloads and validates image from the url, streaming the body and reporting progress
+/// to `listeners`. The request is conditional if any `validators` are given. Waits for its
+/// turn from `scheduler` first. Must be run within tokio context, see
+/// [`Spawner`](self::executor::Spawner).
+pub(crate) async fn download(
+    url: Url,
+    validators: &Validators,
+    listeners: &Listeners,
+    scheduler: &Scheduler,
+) -> Result<Downloaded, FetchError> {
+    let priority = listeners.clone();
+    let _permit = scheduler
+        .acquire(url.host_str().unwrap_or_default(), move || {
+            priority.priority()
+        })
+        .await;
 
-    let response = reqwest::get(url).compat().await?;
-    let bytes = response.bytes().compat().await?;
-    if let Err(e) = sender.send(bytes) {
-        eprintln!("{e}");
+    let mut response = client()
+        .get(url)
+        .headers(validators.headers())
+        .send()
+        .await?;
+
+    let meta = ResponseMeta::from_headers(response.headers());
+
+    if response.status() == StatusCode::NOT_MODIFIED && !validators.is_empty() {
+        return Ok(Downloaded::NotModified(meta));
+    }
+
+    check_response(response.status(), response.headers())?;
+
+    let mut body = Body::new(response.content_length(), listeners);
+
+    while let Some(chunk) = response.chunk().await? {
+        body.push(&chunk);
+    }
+
+    Ok(Downloaded::Modified(body.finish()?, meta))
+}
+
+/// Outcome of [`download`]
+pub(crate) enum Downloaded {
+    Modified(Bytes, ResponseMeta),
+    /// Server responded with `304 Not Modified` to a conditional request
+    NotModified(ResponseMeta),
+}
+
+impl Downloaded {
+    /// For callers that don't send validators, where `NotModified` is unexpected
+    pub(crate) fn into_modified(self) -> Result<(Bytes, ResponseMeta), FetchError> {
+        match self {
+            Downloaded::Modified(bytes, meta) => Ok((bytes, meta)),
+            Downloaded::NotModified(_) => Err(FetchError::Status(StatusCode::NOT_MODIFIED)),
+        }
+    }
+}
+
+/// Response headers used for caching
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ResponseMeta {
+    pub(crate) content_type: Option<String>,
+    pub(crate) validators: Validators,
+    /// How long the response is fresh, from `Cache-Control` or `Expires`
+    pub(crate) max_age: Option<Duration>,
+    /// `Cache-Control: no-store`
+    pub(crate) no_store: bool,
+}
+
+/// Values for a conditional request
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Validators {
+    pub(crate) etag: Option<String>,
+    pub(crate) last_modified: Option<String>,
+}
+
+impl ResponseMeta {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        // Values containing tabs can't be stored in the index
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.contains('\t'))
+                .map(str::to_string)
+        };
+
+        let mut max_age = None;
+        let mut no_cache = false;
+        let mut no_store = false;
+
+        let cache_control = header(CACHE_CONTROL).unwrap_or_default();
+
+        for directive in cache_control.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+
+            match directive.split_once('=') {
+                Some(("max-age", secs)) => {
+                    max_age = secs.trim_matches('"').parse().ok().map(Duration::from_secs);
+                }
+                _ if directive == "no-cache" => no_cache = true,
+                _ if directive == "no-store" => no_store = true,
+                _ => {}
+            }
+        }
+
+        // Time the response already spent in other caches
+        let age = header(AGE)
+            .and_then(|age| age.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_default();
+
+        // Invalid `Expires` means already expired
+        let expires = || {
+            let expires = httpdate::parse_http_date(&header(EXPIRES)?).ok()?;
+            let date = header(DATE)
+                .and_then(|date| httpdate::parse_http_date(&date).ok())
+                .unwrap_or_else(SystemTime::now);
+
+            Some(expires.duration_since(date).unwrap_or_default())
+        };
+
+        let max_age = if no_cache {
+            Some(Duration::ZERO)
+        } else if let Some(max_age) = max_age {
+            Some(max_age.saturating_sub(age))
+        } else if headers.contains_key(EXPIRES) {
+            Some(expires().unwrap_or_default())
+        } else {
+            None
+        };
+
+        Self {
+            content_type: header(CONTENT_TYPE),
+            validators: Validators {
+                etag: header(ETAG),
+                last_modified: header(LAST_MODIFIED),
+            },
+            max_age,
+            no_store,
+        }
+    }
+}
+
+impl Validators {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        if let Some(etag) = self.etag.as_deref().and_then(|v| v.parse().ok()) {
+            headers.insert(IF_NONE_MATCH, etag);
+        }
+
+        if let Some(last_modified) = self.last_modified.as_deref().and_then(|v| v.parse().ok()) {
+            headers.insert(IF_MODIFIED_SINCE, last_modified);
+        }
+
+        headers
+    }
+}
+
+/// Rejects non-2xx responses and responses that declare a non-image content type.
+/// Missing content type and `application/octet-stream` are let through and left for
+/// [`check_image_bytes`] to decide.
+pub(crate) fn check_response(status: StatusCode, headers: &HeaderMap) -> Result<(), FetchError> {
+    if !status.is_success() {
+        return Err(FetchError::Status(status));
+    }
+
+    let Some(content_type) = headers.get(CONTENT_TYPE) else {
+        return Ok(());
+    };
+
+    let content_type = content_type
+        .to_str()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+
+    if mime.starts_with("image/") || mime.ends_with("/octet-stream") {
+        Ok(())
+    } else {
+        Err(FetchError::ContentType(content_type))
     }
+}
 
-    Ok(())
+/// Sniffs magic bytes of the formats that can be decoded
+pub(crate) fn check_image_bytes(bytes: &[u8]) -> Result<(), FetchError> {
+    const SIGNATURES: [&[u8]; 9] = [
+        b"\x89PNG\r\n\x1a\n",
+        b"\xff\xd8\xff",
+        b"GIF87a",
+        b"GIF89a",
+        b"BM",
+        b"\0\0\x01\0",
+        b"II*\0",
+        b"MM\0*",
+        b"qoif",
+    ];
+
+    let is_webp = bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP";
+
+    if is_webp || SIGNATURES.iter().any(|sig| bytes.starts_with(sig)) {
+        Ok(())
+    } else {
+        Err(FetchError::NotAnImage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crossbeam_channel::Receiver;
+    use image::{DynamicImage, ImageFormat};
+    use reqwest::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Bytes {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(width, height)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png.into()
+    }
+
+    fn listener(options: FetchOptions) -> (Listener, Receiver<FetchEvent>) {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        let listener = Listener::new(sender, SharedPriority::default(), options);
+        (listener, receiver)
+    }
+
+    fn thumbnail() -> FetchOptions {
+        FetchOptions {
+            size: Some((4, 4)),
+            ..FetchOptions::default()
+        }
+    }
+
+    fn image_size(event: FetchEvent) -> Option<(u32, u32)> {
+        match event {
+            FetchEvent::Image(image) => image.take().map(|i| (i.width(), i.height())),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn views_decoding_alike_are_grouped() {
+        let progressive = FetchOptions {
+            progressive: Some(Duration::from_millis(100)),
+            ..thumbnail()
+        };
+        let (a, _a) = listener(thumbnail());
+        let (b, _b) = listener(progressive);
+        let (c, _c) = listener(FetchOptions::default());
+
+        let listeners = Listeners::new(a);
+        let _ = listeners.add(b);
+        let _ = listeners.add(c);
+
+        let groups = listeners.0.lock().unwrap().groups(|_| true);
+        let sizes = groups
+            .iter()
+            .map(|(options, senders)| (options.size, senders.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [(Some((4, 4)), 2), (None, 1)]);
+
+        assert_eq!(listeners.progressive(), Some(Duration::from_millis(100)));
+        assert_eq!(listeners.progressive_groups().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn views_get_image_decoded_with_their_options() {
+        let (full, full_rx) = listener(FetchOptions::default());
+        let (small, small_rx) = listener(thumbnail());
+
+        let listeners = Listeners::new(full);
+        let _ = listeners.add(small);
+        listeners.send_image(png(16, 8)).await.unwrap();
+
+        assert_eq!(image_size(full_rx.try_recv().unwrap()), Some((16, 8)));
+        assert_eq!(image_size(small_rx.try_recv().unwrap()), Some((4, 2)));
+    }
+
+    #[tokio::test]
+    async fn views_decoding_alike_get_their_own_copy() {
+        let (a, a_rx) = listener(thumbnail());
+        let (b, b_rx) = listener(thumbnail());
+
+        let listeners = Listeners::new(a);
+        let _ = listeners.add(b);
+        listeners.send_image(png(16, 8)).await.unwrap();
+
+        let a_event = a_rx.try_recv().unwrap();
+        assert_eq!(image_size(a_event.clone()), Some((4, 2)));
+        assert_eq!(image_size(a_event), None, "taken already");
+        assert_eq!(image_size(b_rx.try_recv().unwrap()), Some((4, 2)));
+    }
+
+    #[tokio::test]
+    async fn late_view_catches_up() {
+        let (first, first_rx) = listener(FetchOptions::default());
+        let listeners = Listeners::new(first);
+        listeners.send_image(png(16, 8)).await.unwrap();
+
+        let (late, late_rx) = listener(thumbnail());
+        listeners.add(late).catch_up();
+        let event = late_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(image_size(event), Some((4, 2)));
+
+        first_rx.try_recv().unwrap();
+        listeners.send_error(Arc::new(FetchError::NotAnImage));
+        let (failed, failed_rx) = listener(FetchOptions::default());
+        listeners.add(failed).catch_up();
+        let event = failed_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, FetchEvent::Error(e) if matches!(*e, FetchError::NotAnImage)));
+    }
+
+    fn headers(headers: &[(HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    fn meta(headers: &[(HeaderName, &str)]) -> ResponseMeta {
+        ResponseMeta::from_headers(&self::headers(headers))
+    }
+
+    #[test]
+    fn max_age_excludes_age() {
+        let fresh = meta(&[(CACHE_CONTROL, "public, max-age=600"), (AGE, "100")]);
+        assert_eq!(fresh.max_age, Some(Duration::from_secs(500)));
+        assert!(!fresh.no_store);
+
+        let stale = meta(&[(CACHE_CONTROL, "max-age=\"60\""), (AGE, "100")]);
+        assert_eq!(stale.max_age, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn max_age_takes_precedence_over_expires() {
+        let meta = meta(&[
+            (CACHE_CONTROL, "max-age=60"),
+            (DATE, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            (EXPIRES, "Wed, 21 Oct 2015 08:28:00 GMT"),
+        ]);
+        assert_eq!(meta.max_age, Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn no_cache_is_stale_immediately() {
+        let meta = meta(&[(CACHE_CONTROL, "max-age=600, No-Cache")]);
+        assert_eq!(meta.max_age, Some(Duration::ZERO));
+        assert!(!meta.no_store);
+    }
+
+    #[test]
+    fn no_store_is_reported() {
+        let meta = meta(&[(CACHE_CONTROL, "no-store")]);
+        assert!(meta.no_store);
+        assert_eq!(meta.max_age, None);
+    }
+
+    #[test]
+    fn expires_is_relative_to_date() {
+        let fresh = meta(&[
+            (DATE, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            (EXPIRES, "Wed, 21 Oct 2015 08:28:00 GMT"),
+        ]);
+        assert_eq!(fresh.max_age, Some(Duration::from_secs(3600)));
+
+        let past = meta(&[
+            (DATE, "Wed, 21 Oct 2015 07:28:00 GMT"),
+            (EXPIRES, "Wed, 21 Oct 2015 06:28:00 GMT"),
+        ]);
+        assert_eq!(past.max_age, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn invalid_expires_is_expired() {
+        for expires in ["0", "-1", "tomorrow"] {
+            let meta = meta(&[(EXPIRES, expires)]);
+            assert_eq!(meta.max_age, Some(Duration::ZERO), "{expires}");
+        }
+    }
+
+    #[test]
+    fn no_caching_headers() {
+        let meta = meta(&[(CONTENT_TYPE, "image/png")]);
+        assert_eq!(meta.max_age, None);
+        assert!(!meta.no_store);
+        assert_eq!(meta.content_type.as_deref(), Some("image/png"));
+    }
+
+    #[test]
+    fn validators_are_kept() {
+        let meta = meta(&[
+            (ETAG, "\"abc\""),
+            (LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT"),
+        ]);
+        assert_eq!(meta.validators.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            meta.validators.last_modified.as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+
+        let headers = meta.validators.headers();
+        assert_eq!(headers[IF_NONE_MATCH], "\"abc\"");
+        assert_eq!(headers[IF_MODIFIED_SINCE], "Wed, 21 Oct 2015 07:28:00 GMT");
+    }
+
+    #[test]
+    fn unsuccessful_status_is_rejected() {
+        let png = headers(&[(CONTENT_TYPE, "image/png")]);
+        assert!(check_response(StatusCode::OK, &png).is_ok());
+
+        for status in [
+            StatusCode::NOT_MODIFIED,
+            StatusCode::NOT_FOUND,
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ] {
+            let result = check_response(status, &png);
+            assert!(
+                matches!(result, Err(FetchError::Status(s)) if s == status),
+                "{status}"
+            );
+        }
+    }
+
+    #[test]
+    fn non_image_content_type_is_rejected() {
+        let html = headers(&[(CONTENT_TYPE, "Text/HTML; charset=utf-8")]);
+        let result = check_response(StatusCode::OK, &html);
+        assert!(matches!(result, Err(FetchError::ContentType(c)) if c.starts_with("text/html")));
+
+        for content_type in ["image/webp", "IMAGE/JPEG", "application/octet-stream"] {
+            let headers = headers(&[(CONTENT_TYPE, content_type)]);
+            assert!(
+                check_response(StatusCode::OK, &headers).is_ok(),
+                "{content_type}"
+            );
+        }
+
+        // Left for the bytes to decide
+        assert!(check_response(StatusCode::OK, &HeaderMap::new()).is_ok());
+    }
+
+    #[test]
+    fn image_signatures_are_recognized() {
+        let images: [&[u8]; 10] = [
+            b"\x89PNG\r\n\x1a\n....",
+            b"\xff\xd8\xff\xe0",
+            b"GIF87a..",
+            b"GIF89a..",
+            b"BM......",
+            b"\0\0\x01\0....",
+            b"II*\0....",
+            b"MM\0*....",
+            b"qoif....",
+            b"RIFF\0\0\0\0WEBPVP8 ",
+        ];
+        for bytes in images {
+            assert!(check_image_bytes(bytes).is_ok(), "{bytes:?}");
+        }
+
+        let others: [&[u8]; 5] = [
+            b"",
+            b"<!DOCTYPE html>",
+            b"\x89PN",
+            b"RIFF",
+            b"RIFF\0\0\0\0WEB",
+        ];
+        for bytes in others {
+            assert!(
+                matches!(check_image_bytes(bytes), Err(FetchError::NotAnImage)),
+                "{bytes:?}"
+            );
+        }
+    }
 }
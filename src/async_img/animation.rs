@@ -0,0 +1,203 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use floem::{
+    action::exec_after,
+    reactive::{create_effect, RwSignal, Scope},
+};
+use image::DynamicImage;
+
+use super::ShownImage;
+
+/// Frame delays this short are shown for [`DEFAULT_DELAY`] instead, like browsers do, as
+/// many GIFs are made with a zero delay
+const MIN_DELAY: Duration = Duration::from_millis(10);
+const DEFAULT_DELAY: Duration = Duration::from_millis(100);
+
+/// Frames of an animated GIF, APNG or WebP, each decoded like a still image
+#[derive(Clone, Debug)]
+pub struct Animation {
+    frames: Vec<AnimationFrame>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationFrame {
+    pub image: DynamicImage,
+    /// How long the frame is shown
+    pub delay: Duration,
+}
+
+/// Frame of an animation on the UI thread, ready to be shown
+pub(crate) struct ShownFrame {
+    image: Rc<DynamicImage>,
+    shown_for: Duration,
+}
+
+impl Animation {
+    pub(crate) fn new(frames: Vec<AnimationFrame>) -> Self {
+        Self { frames }
+    }
+
+    #[must_use]
+    pub fn frames(&self) -> &[AnimationFrame] {
+        &self.frames
+    }
+
+    /// Moves the frames over for [`player`]
+    pub(crate) fn into_shown(self) -> Rc<[ShownFrame]> {
+        self.frames
+            .into_iter()
+            .map(|frame| ShownFrame {
+                shown_for: frame.shown_for(),
+                image: Rc::new(frame.image),
+            })
+            .collect()
+    }
+}
+
+impl AnimationFrame {
+    fn shown_for(&self) -> Duration {
+        if self.delay <= MIN_DELAY {
+            DEFAULT_DELAY
+        } else {
+            self.delay
+        }
+    }
+}
+
+/// Controls playback of an animated image, see
+/// [`AsyncImage::animated`](super::AsyncImage::animated). It plays and loops by default.
+#[derive(Clone, Copy)]
+pub struct Playback {
+    playing: RwSignal<bool>,
+    looping: RwSignal<bool>,
+    frame: RwSignal<usize>,
+    /// Stopped on the last frame without looping
+    ended: RwSignal<bool>,
+}
+
+impl Playback {
+    pub(crate) fn new(cx: Scope) -> Self {
+        Self {
+            playing: cx.create_rw_signal(true),
+            looping: cx.create_rw_signal(true),
+            frame: cx.create_rw_signal(0),
+            ended: cx.create_rw_signal(false),
+        }
+    }
+
+    /// Plays from the current frame, or from the start if an animation that doesn't loop
+    /// has ended
+    pub fn play(&self) {
+        if self.ended.get_untracked() {
+            self.seek(0);
+        }
+        self.playing.set(true);
+    }
+
+    pub fn pause(&self) {
+        self.playing.set(false);
+    }
+
+    pub fn toggle(&self) {
+        if self.playing.get_untracked() {
+            self.pause();
+        } else {
+            self.play();
+        }
+    }
+
+    #[must_use]
+    pub fn is_playing(&self) -> bool {
+        self.playing.get()
+    }
+
+    /// Whether the animation starts over after the last frame, otherwise it stops there
+    pub fn set_looping(&self, looping: bool) {
+        self.looping.set(looping);
+    }
+
+    #[must_use]
+    pub fn is_looping(&self) -> bool {
+        self.looping.get()
+    }
+
+    /// Index of the frame shown
+    #[must_use]
+    pub fn frame(&self) -> usize {
+        self.frame.get()
+    }
+
+    /// Shows the frame with this index, the last one if it's out of range
+    pub fn seek(&self, frame: usize) {
+        self.ended.set(false);
+        self.frame.set(frame);
+    }
+
+    /// Starts over for the animation of a new url. It stays paused only if paused by hand.
+    pub(crate) fn restart(&self) {
+        if self.ended.get_untracked() {
+            self.playing.set(true);
+        }
+        self.seek(0);
+    }
+}
+
+/// Shows the frames of `animation` in the buffer while `playback` is playing. Each change
+/// of frame or playback schedules one timer, and the timers scheduled before are ignored.
+pub(crate) fn player(
+    animation: RwSignal<Option<Rc<[ShownFrame]>>>,
+    playback: Playback,
+    buffer: RwSignal<Option<ShownImage>>,
+) {
+    let generation = Rc::new(Cell::new(0_u64));
+
+    // Dropped with the view scope, so that a pending timer doesn't touch disposed signals
+    let stop = StopOnDrop(Rc::clone(&generation));
+    let _ = RwSignal::new(stop);
+
+    create_effect(move |_| {
+        generation.set(generation.get() + 1);
+
+        let Some(frames) = animation.get() else {
+            return;
+        };
+        let last = frames.len().saturating_sub(1);
+        let index = playback.frame.get().min(last);
+        let playing = playback.playing.get();
+
+        let Some(frame) = frames.get(index) else {
+            return;
+        };
+        buffer.set(Some(ShownImage::new(Rc::clone(&frame.image))));
+
+        if !playing || frames.len() < 2 {
+            return;
+        }
+
+        let current = generation.get();
+        let generation = Rc::clone(&generation);
+
+        exec_after(frame.shown_for, move |_| {
+            if generation.get() != current {
+                return;
+            }
+
+            if index < last {
+                playback.frame.set(index + 1);
+            } else if playback.looping.get_untracked() {
+                playback.frame.set(0);
+            } else {
+                playback.ended.set(true);
+                playback.playing.set(false);
+            }
+        });
+    });
+}
+
+struct StopOnDrop(Rc<Cell<u64>>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.set(self.0.get() + 1);
+    }
+}
@@ -1,18 +1,70 @@
 #![allow(clippy::module_name_repetitions)]
 
-use std::{path::PathBuf, sync::Arc, time::Duration};
+mod disk;
+mod memory;
+mod stats;
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use bytes::Bytes;
 use crossbeam_channel::Sender;
-use dashmap::{DashMap, DashSet};
+use dashmap::DashMap;
+use image::DynamicImage;
 use reqwest::Url;
-use xxhash_rust::xxh3::{xxh3_64, Xxh3Builder};
+use xxhash_rust::xxh3::Xxh3Builder;
+
+pub use self::stats::CacheStats;
+use self::{
+    disk::{DiskCache, DiskEntry},
+    memory::MemoryCache,
+    stats::Stats,
+};
+use super::{
+    check_image_bytes,
+    decode::{self, decode},
+    error::FetchError,
+    executor::{Executor, TaskHandle},
+    scheduler::{Scheduler, SharedPriority},
+    send_image, send_unblocked, Downloaded, FetchEvent, FetchGuard, FetchOptions, Handoff,
+    Listener, Listeners, ResponseMeta, Validators,
+};
+
+/// Emits a `tracing` event when the `tracing` feature is enabled
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+);
+    };
+}
+
+/// How often expired entries are removed when `alive_time` isn't set
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
 
 #[derive(Clone)]
 pub struct AsyncCache {
-    map: Arc<DashMap<Url, Bytes, Xxh3Builder>>,
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    memory: MemoryCache,
+    disk: Option<Arc<DiskCache>>,
     config: CacheConfig,
-    fetching: Arc<DashSet<Url, Xxh3Builder>>,
+    /// [`CacheConfig::placeholder`] decoded
+    placeholder: Option<DynamicImage>,
+    fetching: DashMap<Url, InFlight, Xxh3Builder>,
+    /// Urls that failed recently
+    failed: DashMap<Url, Failure, Xxh3Builder>,
+    last_sweep: Mutex<Instant>,
+    stats: Stats,
+    scheduler: Scheduler,
+    executor: Executor,
 }
 
 impl Default for AsyncCache {
@@ -23,257 +75,1061 @@ impl Default for AsyncCache {
 
 #[derive(Default, Clone)]
 pub struct CacheConfig {
+    /// Shown while fetching, for views that don't have a placeholder of their own. Decoded
+    /// once when the cache is created, and copied for each view.
     pub placeholder: Option<Bytes>,
+    /// Directory where fetched images are stored, along with an index of their metadata
     pub local_cache_path: Option<PathBuf>,
+    /// How long fetched image is used before it's revalidated, if the response doesn't say
+    /// with `Cache-Control` or `Expires` headers. Expired images are removed from memory and
+    /// disk periodically, except files with `ETag` or `Last-Modified` which are revalidated
+    /// with a conditional request.
     pub alive_time: Option<Duration>,
+    /// Maximum total size in bytes of images kept in memory. Least recently used images
+    /// are evicted first, and are still available from `local_cache_path` if set. Larger
+    /// images are only cached on disk.
+    pub max_memory_bytes: Option<usize>,
+    /// Maximum number of images kept in memory
+    pub max_memory_entries: Option<usize>,
+    /// Maximum total size in bytes of files in `local_cache_path`. Least recently used files
+    /// are removed first.
+    pub max_disk_bytes: Option<u64>,
+    /// Show expired image right away while it's revalidated, instead of waiting for the server
+    pub stale_while_revalidate: bool,
+    /// How long a failed url isn't fetched again. Views asking for it meanwhile get the same
+    /// error. By default failures are retried right away.
+    pub retry_after: Option<Duration>,
+    /// Keep downloading into the cache after every view waiting for the image is gone.
+    /// By default abandoned fetches are cancelled.
+    pub complete_abandoned: bool,
+    /// Limits downloads of this cache. By default [`Scheduler::global`] is used, which is
+    /// shared with views that don't use the cache.
+    pub scheduler: Option<Scheduler>,
+    /// Runs the fetches of this cache. By default [`Executor::default`] is used.
+    pub executor: Option<Executor>,
+}
+
+/// How a fetch uses the cache tiers. Views asking for an url that is already being fetched
+/// join that fetch, whose policy applies.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Use memory and disk
+    #[default]
+    Normal,
+    /// Download without reading or writing the cache
+    Bypass,
+    /// Don't read or write `local_cache_path`
+    MemoryOnly,
+    /// Don't read or write memory, so that large images don't evict others
+    DiskOnly,
+    /// Download again even if cached, and cache the result
+    Refresh,
+}
+
+impl CachePolicy {
+    fn reads_memory(self) -> bool {
+        matches!(self, CachePolicy::Normal | CachePolicy::MemoryOnly)
+    }
+
+    fn reads_disk(self) -> bool {
+        matches!(self, CachePolicy::Normal | CachePolicy::DiskOnly)
+    }
+
+    fn writes_memory(self) -> bool {
+        matches!(
+            self,
+            CachePolicy::Normal | CachePolicy::MemoryOnly | CachePolicy::Refresh
+        )
+    }
+
+    fn writes_disk(self) -> bool {
+        matches!(
+            self,
+            CachePolicy::Normal | CachePolicy::DiskOnly | CachePolicy::Refresh
+        )
+    }
+}
+
+fn is_stale(expires: Option<SystemTime>) -> bool {
+    expires.is_some_and(|expires| SystemTime::now() > expires)
+}
+
+impl Inner {
+    /// Path and index entry of the local file for the url
+    fn local_entry(&self, url: &Url) -> Option<(PathBuf, DiskEntry)> {
+        let disk = self.disk.as_ref()?;
+        disk.get(url).map(|entry| (disk.file_path(url), entry))
+    }
+
+    /// Tells the views about the error and remembers it for `retry_after`
+    fn fail(&self, url: &Url, error: FetchError, listeners: &Listeners) {
+        eprintln!("Failed to fetch {url}: {error}");
+        let error = Arc::new(error);
+
+        if let Some(retry_after) = self.config.retry_after {
+            self.failed.insert(
+                url.clone(),
+                Failure {
+                    error: Arc::clone(&error),
+                    retry_at: Instant::now() + retry_after,
+                },
+            );
+        }
+
+        listeners.send_error(error);
+    }
+
+    /// Error of a recent failure that shouldn't be retried yet
+    fn failure(&self, url: &Url) -> Option<Arc<FetchError>> {
+        let failed = self
+            .failed
+            .get(url)
+            .filter(|failure| !failure.can_retry())
+            .map(|failure| Arc::clone(&failure.error));
+
+        if failed.is_none() {
+            self.failed.remove(url);
+        }
+        failed
+    }
+
+    /// Counts which tier the fetch found the image in, and how long it took. Tiers the
+    /// `policy` skips aren't counted.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    fn record(
+        &self,
+        url: &Url,
+        found: &Result<Found, FetchError>,
+        policy: CachePolicy,
+        started: Instant,
+    ) {
+        let stats = &self.stats;
+        let elapsed = started.elapsed();
+        let disk_miss = u64::from(self.disk.is_some() && policy.reads_disk());
+
+        stats.record_latency(elapsed);
+
+        match found {
+            Ok(Found {
+                response: None,
+                bytes,
+                revalidation_failed,
+                ..
+            }) => {
+                Stats::add(&stats.disk_hits, 1);
+                trace_event!(debug, %url, ?elapsed, size = bytes.len(), "disk hit");
+
+                if *revalidation_failed {
+                    Stats::add(&stats.network_failures, 1);
+                    trace_event!(warn, %url, ?elapsed, "revalidation failed");
+                }
+            }
+            Ok(Found { bytes, .. }) => {
+                Stats::add(&stats.disk_misses, disk_miss);
+                Stats::add(&stats.network_fetches, 1);
+                Stats::add(&stats.bytes_downloaded, bytes.len() as u64);
+                trace_event!(debug, %url, ?elapsed, size = bytes.len(), "downloaded");
+            }
+            Err(error) => {
+                Stats::add(&stats.disk_misses, disk_miss);
+                Stats::add(&stats.network_failures, 1);
+                trace_event!(warn, %url, ?elapsed, %error, "fetch failed");
+            }
+        }
+    }
+
+    fn remove_local(&self, url: &Url) {
+        if let Some(disk) = &self.disk {
+            disk.remove(url);
+        }
+    }
+
+    /// Freshness from the response takes precedence over `alive_time`
+    fn expires(&self, fetched: SystemTime, max_age: Option<Duration>) -> Option<SystemTime> {
+        max_age
+            .or(self.config.alive_time)
+            .map(|max_age| fetched + max_age)
+    }
+
+    /// Image to use after asking the server about a stale local file. The stale image is used
+    /// if the server can't be reached.
+    fn revalidated(
+        &self,
+        url: &Url,
+        stale: Bytes,
+        entry: &DiskEntry,
+        result: Result<Downloaded, FetchError>,
+        sent: bool,
+    ) -> Found {
+        match result {
+            Ok(Downloaded::NotModified(meta)) => {
+                // Without new freshness info the file is fresh as long as it was before
+                let fetched = SystemTime::now();
+                let expires = self.expires(fetched, meta.max_age.or_else(|| entry.lifetime()));
+
+                if let Some(disk) = &self.disk {
+                    disk.refresh(url, fetched, expires, &meta);
+                }
+
+                Found {
+                    bytes: stale,
+                    expires,
+                    response: None,
+                    sent,
+                    revalidation_failed: false,
+                }
+            }
+            Ok(Downloaded::Modified(bytes, meta)) => Found::network(self, bytes, meta),
+            Err(e) => {
+                eprintln!("Failed to revalidate {url}: {e}");
+
+                Found {
+                    bytes: stale,
+                    expires: entry.expires,
+                    response: None,
+                    sent,
+                    revalidation_failed: true,
+                }
+            }
+        }
+    }
+}
+
+/// Negative cache entry
+struct Failure {
+    error: Arc<FetchError>,
+    retry_at: Instant,
+}
+
+impl Failure {
+    fn can_retry(&self) -> bool {
+        Instant::now() >= self.retry_at
+    }
+}
+
+/// Fetch in progress for an url
+struct InFlight {
+    id: u64,
+    /// Views waiting for the result
+    listeners: Listeners,
+    task: Option<TaskHandle>,
+    /// Prefetched, so it completes even if no view waits for it
+    prefetch: bool,
+    /// Set by [`AsyncCache::invalidate`], so that the result isn't cached
+    invalidated: Arc<AtomicBool>,
+}
+
+impl InFlight {
+    fn new(listeners: Listeners, prefetch: bool) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            listeners,
+            task: None,
+            prefetch,
+            invalidated: Arc::new(AtomicBool::new(false)),
+        }
+    }
 }
 
 impl AsyncCache {
     #[must_use]
     pub fn new() -> Self {
-        AsyncCache {
-            map: Arc::new(DashMap::with_hasher(Xxh3Builder::new())),
-            config: CacheConfig::default(),
-            fetching: Arc::new(DashSet::with_hasher(Xxh3Builder::new())),
-        }
+        Self::with_config(CacheConfig::default())
     }
 
     #[must_use]
-    pub fn with_config(mut config: CacheConfig) -> Self {
-        if let Some(local_cache_path) = &config.local_cache_path {
-            if let Err(e) = std::fs::create_dir_all(local_cache_path) {
-                eprintln!("Cannot create local cache dir: {e}");
-                config.local_cache_path = None;
-            }
+    pub fn with_config(config: CacheConfig) -> Self {
+        let disk = config.local_cache_path.clone().and_then(|path| {
+            DiskCache::open(path, config.max_disk_bytes)
+                .map(Arc::new)
+                .map_err(|e| eprintln!("Cannot open local cache dir: {e}"))
+                .ok()
+        });
+
+        let placeholder = config.placeholder.as_ref().and_then(|bytes| {
+            image::load_from_memory(bytes)
+                .map(decode::rgba)
+                .map_err(|e| eprintln!("Invalid placeholder: {e}"))
+                .ok()
+        });
+
+        let scheduler = config.scheduler.clone().unwrap_or_else(Scheduler::global);
+        let executor = config.executor.clone().unwrap_or_default();
+
+        let cache = AsyncCache {
+            inner: Arc::new(Inner {
+                memory: MemoryCache::new(config.max_memory_bytes, config.max_memory_entries),
+                disk,
+                config,
+                placeholder,
+                fetching: DashMap::with_hasher(Xxh3Builder::new()),
+                failed: DashMap::with_hasher(Xxh3Builder::new()),
+                last_sweep: Mutex::new(Instant::now()),
+                stats: Stats::default(),
+                scheduler,
+                executor,
+            }),
+        };
+
+        cache.sweep_disk();
+        cache
+    }
+
+    /// Sends cached image for the url to `sender` or starts fetching it.
+    /// Returned guard marks the caller as waiting for the fetch until dropped. Every caller
+    /// waiting for the same url gets the image, decoded with its own `options`. A download
+    /// waiting for the scheduler goes by the highest `priority` of its callers.
+    pub fn url(
+        &self,
+        sender: &Sender<FetchEvent>,
+        url: &str,
+        options: FetchOptions,
+        priority: SharedPriority,
+    ) -> Option<FetchGuard> {
+        let inner = &self.inner;
+
+        if let Some(placeholder) = inner.placeholder.as_ref().filter(|_| !options.placeholder) {
+            // Nothing was sent to the view yet, so there's room in its channel
+            let _ = sender.try_send(FetchEvent::Placeholder(Handoff::new(placeholder.clone())));
+        }
+
+        if options.cache_policy == CachePolicy::Bypass {
+            let listeners = Listeners::new(Listener::new(sender.clone(), priority, options));
+            let task = super::fetch(
+                url.to_string(),
+                listeners,
+                inner.scheduler.clone(),
+                &inner.executor,
+            );
+            return Some(FetchGuard::new(move || drop(task)));
+        }
+
+        let Ok(url) = Url::parse(url) else {
+            eprintln!("Invalid url: {url}");
+            return None;
+        };
+
+        self.sweep_if_due();
+
+        if let Some(error) = inner.failure(&url) {
+            send_unblocked(sender.clone(), FetchEvent::Error(error));
+            return None;
+        }
+
+        let reads_memory = options.cache_policy.reads_memory();
+        let cached = reads_memory.then(|| inner.memory.get(&url)).flatten();
+
+        if cached
+            .as_ref()
+            .is_some_and(|(_, expires)| !is_stale(*expires))
+        {
+            Stats::add(&inner.stats.memory_hits, 1);
+            trace_event!(debug, %url, "memory hit");
+        } else if reads_memory {
+            Stats::add(&inner.stats.memory_misses, 1);
+            trace_event!(debug, %url, "memory miss");
         }
 
-        AsyncCache {
-            map: Arc::new(DashMap::with_hasher(Xxh3Builder::new())),
-            config,
-            fetching: Arc::new(DashSet::with_hasher(Xxh3Builder::new())),
+        let mut stale = None;
+
+        if let Some((bytes, expires)) = cached {
+            if !is_stale(expires) {
+                let sender = sender.clone();
+
+                let task = blocking::unblock(move || match decode(&bytes, options) {
+                    Ok(image) => send_image(&sender, image),
+                    Err(e) => eprintln!("{e}"),
+                });
+                return Some(FetchGuard::new(move || drop(task)));
+            }
+
+            // Shown until revalidated. Sent by the fetch, so that it comes before the new image.
+            if inner.config.stale_while_revalidate {
+                stale = Some(bytes);
+            }
         }
+
+        let listener = Listener::new(sender.clone(), priority, options);
+        let id = self.join_fetch(url.clone(), options.cache_policy, Some(listener), stale);
+
+        Some(self.release_guard(url, id, sender.clone()))
     }
 
-    pub fn url(&self, sender: &Sender<Bytes>, url: &str) {
-        if let Some(placeholder) = &self.config.placeholder {
-            if let Err(e) = sender.send(placeholder.clone()) {
-                eprintln!("{e}");
+    /// Starts fetching the urls in the background, so that views showing them later find
+    /// them in memory or join the fetch. Fresh images already in memory are skipped.
+    /// Prefetches have low priority until a view joins them, and are only decoded for views.
+    pub fn prefetch<I>(&self, urls: I)
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        for url in urls {
+            let url = url.as_ref();
+            let Ok(url) = Url::parse(url) else {
+                eprintln!("Invalid url: {url}");
+                continue;
+            };
+
+            let fresh = self
+                .inner
+                .memory
+                .get(&url)
+                .is_some_and(|(_, expires)| !is_stale(expires));
+
+            if !fresh && self.inner.failure(&url).is_none() {
+                self.join_fetch(url, CachePolicy::default(), None, None);
             }
         }
+    }
 
+    /// Removes the image for the url from memory and disk. A fetch of the url in progress
+    /// still delivers its image to the views waiting for it, but doesn't cache it.
+    pub fn invalidate(&self, url: &str) {
         let Ok(url) = Url::parse(url) else {
-            eprintln!("Invalid url: {url}");
             return;
         };
 
-        if let Some(val) = self.map.get(&url) {
-            if let Err(e) = sender.send(val.clone()) {
-                eprintln!("{e}");
+        // Marked first, so that the fetch either sees it or writes before the removal below
+        if let Some(in_flight) = self.inner.fetching.get(&url) {
+            in_flight.invalidated.store(true, Ordering::Release);
+        }
+
+        self.inner.memory.remove(&url);
+        self.inner.remove_local(&url);
+        self.inner.failed.remove(&url);
+    }
+
+    pub fn clear_memory(&self) {
+        self.inner.memory.clear();
+    }
+
+    /// Removes every file in `local_cache_path`. Blocks until done.
+    pub fn clear_disk(&self) {
+        if let Some(disk) = &self.inner.disk {
+            disk.clear();
+        }
+    }
+
+    /// Whether the image for the url is in memory or on disk, even if expired
+    #[must_use]
+    pub fn contains(&self, url: &str) -> bool {
+        let Ok(url) = Url::parse(url) else {
+            return false;
+        };
+
+        self.inner.memory.contains(&url)
+            || self
+                .inner
+                .disk
+                .as_ref()
+                .is_some_and(|disk| disk.contains(&url))
+    }
+
+    /// Cached image for the url as it was fetched, even if expired. Reads from disk if it's
+    /// not in memory.
+    #[must_use]
+    pub fn get(&self, url: &str) -> Option<Bytes> {
+        let url = Url::parse(url).ok()?;
+
+        match self.inner.memory.get(&url) {
+            Some((bytes, _)) => Some(bytes),
+            None => self.inner.disk.as_ref()?.read(&url),
+        }
+    }
+
+    /// Caches an image that was obtained some other way. It expires after `alive_time`.
+    ///
+    /// # Errors
+    ///
+    /// If the url is invalid, the bytes are not an image, or writing to disk fails with
+    /// [`FetchError::Disk`]
+    pub fn insert(&self, url: &str, bytes: impl Into<Bytes>) -> Result<(), FetchError> {
+        let url = Url::parse(url).map_err(FetchError::Url)?;
+        let bytes = bytes.into();
+        check_image_bytes(&bytes)?;
+
+        let fetched = SystemTime::now();
+        let expires = self.inner.expires(fetched, None);
+
+        self.inner.failed.remove(&url);
+        self.inner
+            .memory
+            .insert(url.clone(), bytes.clone(), expires);
+
+        if let Some(disk) = &self.inner.disk {
+            disk.store(&url, &bytes, fetched, expires, &ResponseMeta::default())
+                .map_err(FetchError::Disk)?;
+        }
+
+        Ok(())
+    }
+
+    /// Snapshot of the counters since the cache was created
+    #[must_use]
+    pub fn stats(&self) -> CacheStats {
+        let inner = &self.inner;
+
+        CacheStats {
+            memory_evictions: inner.memory.evictions(),
+            disk_evictions: inner.disk.as_ref().map_or(0, |disk| disk.evictions()),
+            in_flight: inner.fetching.len(),
+            ..inner.stats.snapshot()
+        }
+    }
+
+    /// Adds the view to the fetch of the url, starting one with `policy` if needed. Without
+    /// a view the fetch is a prefetch. A fetch that starts sends the `stale` image first, a
+    /// running one has sent its own. Returns id of the fetch.
+    fn join_fetch(
+        &self,
+        url: Url,
+        policy: CachePolicy,
+        listener: Option<Listener>,
+        stale: Option<Bytes>,
+    ) -> u64 {
+        let inner = &self.inner;
+        let prefetch = listener.is_none();
+
+        let (id, joined) = match inner.fetching.entry(url.clone()) {
+            // Added while the entry is locked, so that the fetch isn't abandoned meanwhile
+            dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+                let in_flight = entry.get_mut();
+                in_flight.prefetch |= prefetch;
+                (
+                    in_flight.id,
+                    listener.map(|listener| in_flight.listeners.add(listener)),
+                )
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                let listeners = listener.map(Listeners::new).unwrap_or_default();
+
+                let (id, listeners, invalidated) = {
+                    let in_flight = entry.insert(InFlight::new(listeners, prefetch));
+                    (
+                        in_flight.id,
+                        in_flight.listeners.clone(),
+                        Arc::clone(&in_flight.invalidated),
+                    )
+                };
+                let task = inner.executor.spawn(async_fetch(
+                    Arc::clone(inner),
+                    url.clone(),
+                    id,
+                    policy,
+                    listeners,
+                    invalidated,
+                    stale,
+                ));
+
+                // Fetch may have finished already, in which case the handle is just dropped
+                if let Some(mut in_flight) = inner.fetching.get_mut(&url) {
+                    if in_flight.id == id {
+                        in_flight.task = Some(task);
+                    }
+                }
+
+                return id;
+            }
+        };
+
+        // The entry is unlocked by now
+        if let Some(joined) = joined {
+            joined.catch_up();
+        }
+        id
+    }
+
+    fn release_guard(&self, url: Url, id: u64, sender: Sender<FetchEvent>) -> FetchGuard {
+        let inner = Arc::clone(&self.inner);
+
+        FetchGuard::new(move || {
+            let abandoned = inner
+                .fetching
+                .get(&url)
+                .filter(|in_flight| in_flight.id == id)
+                .is_some_and(|in_flight| {
+                    in_flight.listeners.remove(&sender) == 0 && !in_flight.prefetch
+                });
+
+            if abandoned && !inner.config.complete_abandoned {
+                // Checked again as another view may have joined meanwhile. Dropping the task
+                // handles cancels them.
+                inner.fetching.remove_if(&url, |_, in_flight| {
+                    in_flight.id == id && in_flight.listeners.is_empty() && !in_flight.prefetch
+                });
             }
-            return;
+        })
+    }
+
+    /// Removes expired entries if `alive_time`, or by default [`SWEEP_INTERVAL`], has passed
+    /// since the last sweep
+    fn sweep_if_due(&self) {
+        let interval = self.inner.config.alive_time.unwrap_or(SWEEP_INTERVAL);
+
+        {
+            let mut last_sweep = self.inner.last_sweep.lock().unwrap();
+            if last_sweep.elapsed() < interval {
+                return;
+            }
+            *last_sweep = Instant::now();
         }
 
-        if !self.fetching.contains(&url) {
-            self.fetching.insert(url.clone());
-            self.fetch(url, sender.clone());
+        self.inner
+            .memory
+            .retain(|_, entry| !is_stale(entry.expires));
+        self.inner.failed.retain(|_, failure| !failure.can_retry());
+
+        self.sweep_disk();
+    }
+
+    /// Removes expired files from the local cache dir on the blocking thread pool
+    fn sweep_disk(&self) {
+        if let Some(disk) = &self.inner.disk {
+            let disk = Arc::clone(disk);
+            let alive_time = self.inner.config.alive_time;
+            blocking::unblock(move || disk.remove_expired(alive_time)).detach();
+        }
+    }
+}
+
+/// Image found by a fetch task
+struct Found {
+    bytes: Bytes,
+    expires: Option<SystemTime>,
+    /// Fetch time and headers of images from network, which are written to disk
+    response: Option<(SystemTime, ResponseMeta)>,
+    /// Already sent to the view while revalidating
+    sent: bool,
+    /// Stale file is used because the server couldn't be reached
+    revalidation_failed: bool,
+}
+
+impl Found {
+    fn local(bytes: Bytes, entry: &DiskEntry, alive_time: Option<Duration>) -> Self {
+        Self {
+            bytes,
+            expires: entry.expires_at(alive_time),
+            response: None,
+            sent: false,
+            revalidation_failed: false,
         }
     }
 
-    fn fetch(&self, url: Url, sender: Sender<Bytes>) {
-        let shared_map = Arc::clone(&self.map);
-        let shared_fetchlist = Arc::clone(&self.fetching);
+    fn network(inner: &Inner, bytes: Bytes, meta: ResponseMeta) -> Self {
+        let fetched = SystemTime::now();
+
+        Self {
+            bytes,
+            expires: inner.expires(fetched, meta.max_age),
+            response: Some((fetched, meta)),
+            sent: false,
+            revalidation_failed: false,
+        }
+    }
 
-        let local_file_path = self
-            .config
-            .local_cache_path
+    /// Responses with `Cache-Control: no-store` are only shown
+    fn is_cacheable(&self) -> bool {
+        !self
+            .response
             .as_ref()
-            .map(|p| p.join(xxh3_64(url.as_str().as_bytes()).to_string()));
-
-        #[cfg(feature = "async-std")]
-        async_std::task::spawn(async_fetch(
-            url,
-            local_file_path,
-            shared_map,
-            shared_fetchlist,
-            sender,
-        ));
-
-        #[cfg(feature = "tokio")]
-        tokio::spawn(async_fetch(
-            url,
-            local_file_path,
-            shared_map,
-            shared_fetchlist,
-            sender,
-        ));
-
-        #[cfg(feature = "smol")]
-        smol::spawn(async_fetch(
-            url,
-            local_file_path,
-            shared_map,
-            shared_fetchlist,
-            sender,
-        ))
-        .detach();
-
-        #[cfg(feature = "thread")]
-        std::thread::spawn(move || {
-            sync_fetch(
-                &url,
-                local_file_path,
-                &shared_map,
-                &shared_fetchlist,
-                &sender,
-            );
-        });
+            .is_some_and(|(_, meta)| meta.no_store)
     }
 }
 
-#[cfg(any(feature = "async-std", feature = "tokio", feature = "smol"))]
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(level = "debug", skip_all, fields(%url, id = id))
+)]
 async fn async_fetch(
+    inner: Arc<Inner>,
     url: Url,
-    local_file_path: Option<PathBuf>,
-    shared_map: Arc<DashMap<Url, Bytes, Xxh3Builder>>,
-    shared_fetchlist: Arc<DashSet<Url, Xxh3Builder>>,
-    sender: Sender<Bytes>,
+    id: u64,
+    policy: CachePolicy,
+    listeners: Listeners,
+    invalidated: Arc<AtomicBool>,
+    stale: Option<Bytes>,
 ) {
-    use futures::{future::Either, pin_mut};
+    let alive_time = inner.config.alive_time;
+    let started = Instant::now();
 
-    let read_local = async { read_path(local_file_path.clone()).await.ok() };
-    pin_mut!(read_local);
+    // Stale image from memory
+    let mut sent = false;
+    if let Some(stale) = stale {
+        sent = listeners.send_image(stale).await.is_ok();
+    }
 
-    let fetch_url = async { fetch(&url).await.ok() };
-    pin_mut!(fetch_url);
-    let (bytes, _) = futures::future::select(read_local, fetch_url)
-        .await
-        .factor_first();
+    let local = policy
+        .reads_disk()
+        .then(|| inner.local_entry(&url))
+        .flatten();
 
-    if let Some(bytes) = bytes {
-        sender.send(bytes.clone()).unwrap();
-        shared_map.insert(url.clone(), bytes.clone());
-        let _ = shared_fetchlist.remove(&url);
+    // Network is only used if there's no local file, or it has expired
+    let found = match local {
+        Some((path, entry)) if !entry.is_expired(alive_time) || entry.can_revalidate() => {
+            match read_path(&path).await.ok().filter(|b| entry.is_intact(b)) {
+                Some(bytes) if !entry.is_expired(alive_time) => {
+                    Ok(Found::local(bytes, &entry, alive_time))
+                }
+                Some(stale) => {
+                    let sent = sent
+                        || inner.config.stale_while_revalidate
+                            && listeners.send_image(stale.clone()).await.is_ok();
 
-        if let Some(local) = &local_file_path {
-            let _ = write_bytes(local, bytes).await;
+                    let result = download(&inner, &url, &entry.validators, &listeners).await;
+                    Ok(inner.revalidated(&url, stale, &entry, result, sent))
+                }
+                None => {
+                    // File was removed or corrupted behind our back
+                    inner.remove_local(&url);
+                    fetch_full(&inner, &url, &listeners).await
+                }
+            }
         }
-    }
-}
+        _ => fetch_full(&inner, &url, &listeners).await,
+    };
 
-#[cfg(feature = "thread")]
-fn sync_fetch(
-    url: &Url,
-    local_file_path: Option<PathBuf>,
-    shared_map: &Arc<DashMap<Url, Bytes, Xxh3Builder>>,
-    shared_fetchlist: &Arc<DashSet<Url, Xxh3Builder>>,
-    sender: &Sender<Bytes>,
-) {
-    let handle_ok = |bytes: Bytes| {
-        let _ = sender.send(bytes.clone());
-        shared_map.insert(url.clone(), bytes);
-        let _ = shared_fetchlist.remove(url);
+    inner.record(&url, &found, policy, started);
+
+    let found = match found {
+        Ok(found) if !found.sent => listeners
+            .send_image(found.bytes.clone())
+            .await
+            .map(|()| found),
+        found => found,
     };
 
-    // Try local
-    if let Some(local_path) = &local_file_path {
-        if let Ok(bytes) = std::fs::read(local_path) {
-            handle_ok(bytes.into());
-            return;
-        }
-    }
+    match found {
+        Ok(found) if found.is_cacheable() && !invalidated.load(Ordering::Acquire) => {
+            if policy.writes_memory() {
+                inner
+                    .memory
+                    .insert(url.clone(), found.bytes.clone(), found.expires);
+            }
 
-    // Fetch
-    if let Ok(bytes) = fetch(url) {
-        handle_ok(bytes.clone());
+            let disk = inner.disk.as_ref().filter(|_| policy.writes_disk());
 
-        if let Some(local_path) = local_file_path {
-            let _ = write_bytes(&local_path, bytes);
+            if let (Some(disk), Some((fetched, meta))) = (disk, &found.response) {
+                if write_bytes(&disk.file_path(&url), found.bytes.clone())
+                    .await
+                    .is_ok()
+                {
+                    disk.insert(&url, &found.bytes, *fetched, found.expires, meta);
+                }
+            }
+
+            // Invalidated while being written, which removed it before it was written
+            if invalidated.load(Ordering::Acquire) {
+                inner.memory.remove(&url);
+                inner.remove_local(&url);
+            }
         }
+        Ok(_) => {}
+        Err(e) => inner.fail(&url, e, &listeners),
     }
+
+    // Removed last, as this drops and cancels the handle of this task
+    inner
+        .fetching
+        .remove_if(&url, |_, in_flight| in_flight.id == id);
 }
 
-#[cfg(any(feature = "async-std", feature = "smol"))]
-async fn fetch(url: &Url) -> Result<Bytes, reqwest::Error> {
-    use async_compat::CompatExt;
+/// Fetches without validators
+async fn fetch_full(inner: &Inner, url: &Url, listeners: &Listeners) -> Result<Found, FetchError> {
+    let (bytes, meta) = download(inner, url, &Validators::default(), listeners)
+        .await?
+        .into_modified()?;
 
-    let response = reqwest::get(url.clone()).compat().await?;
-    let bytes = response.bytes().compat().await?;
+    Ok(Found::network(inner, bytes, meta))
+}
 
-    Ok(bytes)
+async fn download(
+    inner: &Inner,
+    url: &Url,
+    validators: &Validators,
+    listeners: &Listeners,
+) -> Result<Downloaded, FetchError> {
+    super::download(url.clone(), validators, listeners, &inner.scheduler).await
 }
 
-#[cfg(feature = "tokio")]
-async fn fetch(url: &Url) -> Result<Bytes, reqwest::Error> {
-    let response = reqwest::get(url.clone()).await?;
-    let bytes = response.bytes().await?;
-    Ok(bytes)
+/// File reads and writes happen on the blocking thread pool, whatever the executor
+async fn read_path(path: &Path) -> Result<Bytes, std::io::Error> {
+    let path = path.to_path_buf();
+    blocking::unblock(move || std::fs::read(path).map(Bytes::from)).await
 }
 
-#[cfg(feature = "thread")]
-fn fetch(url: &Url) -> Result<Bytes, reqwest::Error> {
-    let response = reqwest::blocking::get(url.clone())?;
-    let bytes = response.bytes()?;
-    Ok(bytes)
+async fn write_bytes(path: &Path, bytes: Bytes) -> Result<(), std::io::Error> {
+    let path = path.to_path_buf();
+    blocking::unblock(move || disk::write_file(&path, &bytes)).await
 }
 
-#[cfg(any(feature = "async-std", feature = "tokio", feature = "smol"))]
-async fn read_path(path: Option<PathBuf>) -> Result<Bytes, Box<dyn std::error::Error>> {
-    let Some(path) = path else {
-        futures::future::pending::<()>().await;
-        return Ok(Bytes::default());
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+        thread,
     };
 
-    if !path.exists() {
-        futures::future::pending::<()>().await;
-        return Ok(Bytes::default());
+    use crossbeam_channel::Receiver;
+    use image::ImageFormat;
+
+    use super::*;
+
+    const URL: &str = "http://127.0.0.1:1/cached.png";
+    /// Nothing listens there, so downloads fail right away
+    const UNREACHABLE: &str = "http://127.0.0.1:1/missing.png";
+
+    fn png() -> Bytes {
+        let mut png = Vec::new();
+        DynamicImage::new_rgba8(2, 2)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        png.into()
+    }
+
+    fn on_disk(dir: &tempfile::TempDir) -> AsyncCache {
+        AsyncCache::with_config(CacheConfig {
+            local_cache_path: Some(dir.path().to_path_buf()),
+            ..CacheConfig::default()
+        })
     }
 
-    #[cfg(feature = "smol")]
-    let bytes = smol::fs::read(path).await?.into();
+    fn view(
+        cache: &AsyncCache,
+        url: &str,
+        cache_policy: CachePolicy,
+    ) -> (Option<FetchGuard>, Receiver<FetchEvent>) {
+        let (sender, receiver) = crossbeam_channel::unbounded();
+        let options = FetchOptions {
+            cache_policy,
+            ..FetchOptions::default()
+        };
+        let guard = cache.url(&sender, url, options, SharedPriority::default());
+        (guard, receiver)
+    }
 
-    #[cfg(feature = "async-std")]
-    let bytes = async_std::fs::read(path).await?.into();
+    /// Waits for the image or the error
+    fn outcome(receiver: &Receiver<FetchEvent>) -> FetchEvent {
+        loop {
+            let event = receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+            if matches!(event, FetchEvent::Image(_) | FetchEvent::Error(_)) {
+                return event;
+            }
+        }
+    }
 
-    #[cfg(feature = "tokio")]
-    let bytes = tokio::fs::read(path).await?.into();
+    /// Serves `body` to one request once told to. Url of the server and the sender that
+    /// tells it. Dropping the sender closes the connection instead.
+    fn serve_once(body: Bytes) -> (String, mpsc::Sender<()>) {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/image.png", server.local_addr().unwrap());
+        let (respond, told) = mpsc::channel();
 
-    Ok(bytes)
-}
+        thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let _ = stream.read(&mut [0; 4096]);
 
-#[cfg(feature = "thread")]
-fn read_path(path: Option<PathBuf>) -> Result<Bytes, std::io::Error> {
-    let Some(path) = path else {
-        return Ok(Bytes::default());
-    };
+            if told.recv().is_ok() {
+                let head = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&body);
+            }
+        });
 
-    if !path.exists() {
-        return Ok(Bytes::default());
+        (url, respond)
     }
 
-    let bytes = std::fs::read(path)?.into();
-    Ok(bytes)
-}
+    fn wait_until(done: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
 
-#[cfg(feature = "async-std")]
-async fn write_bytes(path: &PathBuf, bytes: Bytes) -> Result<(), async_std::io::Error> {
-    async_std::fs::write(path, bytes).await?;
-    Ok(())
-}
+    #[test]
+    fn tiers_skipped_by_the_policy_are_not_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = on_disk(&dir);
+        cache.insert(URL, png()).unwrap();
 
-#[cfg(feature = "thread")]
-fn write_bytes(path: &PathBuf, bytes: Bytes) -> Result<(), std::io::Error> {
-    std::fs::write(path, bytes)?;
-    Ok(())
-}
+        let (_guard, receiver) = view(&cache, URL, CachePolicy::Normal);
+        assert!(matches!(outcome(&receiver), FetchEvent::Image(_)));
 
-#[cfg(feature = "smol")]
-async fn write_bytes(path: &PathBuf, bytes: Bytes) -> Result<(), smol::io::Error> {
-    smol::fs::write(path, bytes).await?;
-    Ok(())
-}
+        let (_guard, receiver) = view(&cache, URL, CachePolicy::DiskOnly);
+        assert!(matches!(outcome(&receiver), FetchEvent::Image(_)));
+
+        let (_guard, receiver) = view(&cache, UNREACHABLE, CachePolicy::MemoryOnly);
+        assert!(matches!(outcome(&receiver), FetchEvent::Error(_)));
+
+        let (_guard, receiver) = view(&cache, URL, CachePolicy::Refresh);
+        assert!(matches!(outcome(&receiver), FetchEvent::Error(_)));
+
+        let stats = cache.stats();
+        assert_eq!((stats.memory_hits, stats.memory_misses), (1, 1));
+        assert_eq!((stats.disk_hits, stats.disk_misses), (1, 0));
+        assert_eq!((stats.network_fetches, stats.network_failures), (0, 2));
+        assert!(stats.average_fetch_latency.is_some());
+    }
+
+    #[test]
+    fn failed_revalidation_is_a_disk_hit_and_a_network_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = on_disk(&dir);
+
+        let now = SystemTime::now();
+        let meta = ResponseMeta {
+            validators: Validators {
+                etag: Some("\"1\"".to_string()),
+                last_modified: None,
+            },
+            ..ResponseMeta::default()
+        };
+        let disk = cache.inner.disk.as_ref().unwrap();
+        disk.store(
+            &Url::parse(URL).unwrap(),
+            &png(),
+            now - Duration::from_secs(120),
+            Some(now - Duration::from_secs(60)),
+            &meta,
+        )
+        .unwrap();
+
+        let (_guard, receiver) = view(&cache, URL, CachePolicy::Normal);
+        assert!(matches!(outcome(&receiver), FetchEvent::Image(_)));
+
+        let stats = cache.stats();
+        assert_eq!((stats.memory_hits, stats.memory_misses), (0, 1));
+        assert_eq!((stats.disk_hits, stats.disk_misses), (1, 0));
+        assert_eq!((stats.network_fetches, stats.network_failures), (0, 1));
+    }
+
+    #[test]
+    fn stats_include_evictions() {
+        let cache = AsyncCache::with_config(CacheConfig {
+            max_memory_entries: Some(1),
+            ..CacheConfig::default()
+        });
+        cache.insert(URL, png()).unwrap();
+        cache.insert(UNREACHABLE, png()).unwrap();
 
-#[cfg(feature = "tokio")]
-async fn write_bytes(path: &PathBuf, bytes: Bytes) -> Result<(), tokio::io::Error> {
-    tokio::fs::write(path, bytes).await?;
-    Ok(())
+        let stats = cache.stats();
+        assert_eq!(stats.memory_evictions, 1);
+        assert_eq!(stats.disk_evictions, 0);
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.average_fetch_latency, None);
+    }
+
+    #[test]
+    fn inserted_image_is_cached_until_invalidated() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = on_disk(&dir);
+        let path = cache
+            .inner
+            .disk
+            .as_ref()
+            .unwrap()
+            .file_path(&Url::parse(URL).unwrap());
+
+        assert!(!cache.contains(URL));
+        cache.insert(URL, png()).unwrap();
+        assert!(cache.contains(URL));
+        assert_eq!(cache.get(URL), Some(png()));
+
+        // Read from disk
+        cache.clear_memory();
+        assert!(cache.contains(URL));
+        assert_eq!(cache.get(URL), Some(png()));
+
+        cache.invalidate(URL);
+        assert!(!cache.contains(URL));
+        assert_eq!(cache.get(URL), None);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn insert_rejects_invalid_input() {
+        let cache = AsyncCache::new();
+
+        let html = cache.insert(URL, &b"<!DOCTYPE html>"[..]);
+        assert!(matches!(html, Err(FetchError::NotAnImage)));
+
+        let url = cache.insert("not an url", png());
+        assert!(matches!(url, Err(FetchError::Url(_))));
+        assert!(!cache.contains(URL));
+    }
+
+    #[test]
+    fn corrupt_file_is_evicted_through_get() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = on_disk(&dir);
+        cache.insert(URL, png()).unwrap();
+        cache.clear_memory();
+
+        let disk = cache.inner.disk.as_ref().unwrap();
+        std::fs::write(disk.file_path(&Url::parse(URL).unwrap()), b"garbage").unwrap();
+
+        assert_eq!(cache.get(URL), None);
+        assert!(!cache.contains(URL));
+    }
+
+    #[test]
+    fn tiers_are_cleared_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = on_disk(&dir);
+        cache.insert(URL, png()).unwrap();
+
+        cache.clear_disk();
+        assert_eq!(cache.get(URL), Some(png()));
+
+        cache.clear_memory();
+        assert!(!cache.contains(URL));
+        assert_eq!(cache.get(URL), None);
+
+        // Only the index is left
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn prefetch_skips_fresh_images_and_joins_running_fetches() {
+        let cache = AsyncCache::new();
+        cache.insert(URL, png()).unwrap();
+
+        let (url, respond) = serve_once(png());
+        cache.prefetch([URL, &url, &url]);
+        assert_eq!(cache.stats().in_flight, 1);
+
+        respond.send(()).unwrap();
+        wait_until(|| cache.stats().in_flight == 0);
+
+        assert_eq!(cache.get(&url), Some(png()));
+        assert_eq!(cache.stats().network_fetches, 1);
+    }
+
+    #[test]
+    fn image_invalidated_while_fetched_is_shown_but_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = on_disk(&dir);
+
+        let (url, respond) = serve_once(png());
+        let (_guard, receiver) = view(&cache, &url, CachePolicy::Normal);
+
+        cache.invalidate(&url);
+        let in_flight = cache.inner.fetching.get(&Url::parse(&url).unwrap());
+        assert!(in_flight.unwrap().invalidated.load(Ordering::Acquire));
+
+        respond.send(()).unwrap();
+        assert!(matches!(outcome(&receiver), FetchEvent::Image(_)));
+        wait_until(|| cache.stats().in_flight == 0);
+
+        assert!(!cache.contains(&url));
+        assert_eq!(cache.stats().network_fetches, 1);
+    }
 }
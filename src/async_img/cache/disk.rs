@@ -0,0 +1,747 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use reqwest::Url;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::async_img::{ResponseMeta, Validators};
+
+const INDEX_FILE: &str = "index.tsv";
+const INDEX_HEADER: &str = "floem-things-cache-index 3";
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Disk tier of the cache. Images are stored as files named by the hash of their url, and
+/// metadata about them in an index file in the same directory.
+pub(super) struct DiskCache {
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    index: Mutex<Index>,
+    /// Serializes index writes so that an older snapshot never overwrites a newer one
+    save_lock: Mutex<()>,
+    /// Files removed by [`DiskCache::prune`]
+    evictions: AtomicU64,
+}
+
+#[derive(Default)]
+struct Index {
+    /// Keyed by file name
+    entries: HashMap<String, DiskEntry>,
+    /// Total size of the files
+    total: u64,
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct DiskEntry {
+    /// Unknown for files found without an index entry
+    pub(super) url: Option<String>,
+    pub(super) size: u64,
+    /// `xxh3_64` of the file, unknown for files found without an index entry
+    pub(super) checksum: Option<u64>,
+    pub(super) fetched: SystemTime,
+    pub(super) last_access: SystemTime,
+    pub(super) expires: Option<SystemTime>,
+    pub(super) content_type: Option<String>,
+    pub(super) validators: Validators,
+}
+
+impl DiskEntry {
+    /// Entries without explicit expiry fall back to `alive_time` counted from the fetch
+    pub(super) fn expires_at(&self, alive_time: Option<Duration>) -> Option<SystemTime> {
+        self.expires
+            .or_else(|| alive_time.map(|alive_time| self.fetched + alive_time))
+    }
+
+    pub(super) fn is_expired(&self, alive_time: Option<Duration>) -> bool {
+        super::is_stale(self.expires_at(alive_time))
+    }
+
+    /// How long the entry was fresh when fetched
+    pub(super) fn lifetime(&self) -> Option<Duration> {
+        self.expires?.duration_since(self.fetched).ok()
+    }
+
+    /// Whether bytes read from the file are the ones that were written
+    pub(super) fn is_intact(&self, bytes: &[u8]) -> bool {
+        if bytes.len() as u64 != self.size {
+            return false;
+        }
+
+        match self.checksum {
+            Some(checksum) => checksum == xxh3_64(bytes),
+            None => true,
+        }
+    }
+
+    /// Whether the server can be asked if the file is still valid after it expires
+    pub(super) fn can_revalidate(&self) -> bool {
+        self.url.is_some() && !self.validators.is_empty()
+    }
+}
+
+impl DiskCache {
+    /// Loads the index from `dir`. Missing or corrupted index is rebuilt from the files in the
+    /// directory, and index entries without a file are dropped.
+    pub(super) fn open(dir: PathBuf, max_bytes: Option<u64>) -> io::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+
+        let mut index = match std::fs::read_to_string(dir.join(INDEX_FILE)) {
+            Ok(text) => Index::parse(&text).unwrap_or_else(|| {
+                eprintln!("Disk cache index is corrupted, rebuilding");
+                Index::default()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Index::default(),
+            Err(e) => return Err(e),
+        };
+
+        index.reconcile(&dir)?;
+
+        let cache = Self {
+            dir,
+            max_bytes,
+            index: Mutex::new(index),
+            save_lock: Mutex::new(()),
+            evictions: AtomicU64::new(0),
+        };
+
+        cache.prune(&mut cache.index.lock().unwrap());
+        cache.save();
+
+        Ok(cache)
+    }
+
+    pub(super) fn file_path(&self, url: &Url) -> PathBuf {
+        self.dir.join(file_name(url))
+    }
+
+    /// Returns the entry for the url, marking it as recently used
+    pub(super) fn get(&self, url: &Url) -> Option<DiskEntry> {
+        let mut index = self.index.lock().unwrap();
+        let entry = index.entries.get_mut(&file_name(url))?;
+        entry.last_access = SystemTime::now();
+        Some(entry.clone())
+    }
+
+    /// Records a file that was written for the url, pruning old files if over the size limit
+    pub(super) fn insert(
+        self: &Arc<Self>,
+        url: &Url,
+        bytes: &[u8],
+        fetched: SystemTime,
+        expires: Option<SystemTime>,
+        meta: &ResponseMeta,
+    ) {
+        let entry = DiskEntry {
+            url: Some(url.to_string()),
+            size: bytes.len() as u64,
+            checksum: Some(xxh3_64(bytes)),
+            fetched,
+            last_access: SystemTime::now(),
+            expires,
+            content_type: meta.content_type.clone(),
+            validators: meta.validators.clone(),
+        };
+
+        {
+            let mut index = self.index.lock().unwrap();
+            index.insert(file_name(url), entry);
+            self.prune(&mut index);
+        }
+
+        self.persist();
+    }
+
+    /// Writes the file and records it
+    pub(super) fn store(
+        self: &Arc<Self>,
+        url: &Url,
+        bytes: &[u8],
+        fetched: SystemTime,
+        expires: Option<SystemTime>,
+        meta: &ResponseMeta,
+    ) -> io::Result<()> {
+        write_file(&self.file_path(url), bytes)?;
+        self.insert(url, bytes, fetched, expires, meta);
+        Ok(())
+    }
+
+    /// Reads and verifies the file for the url. Corrupt file is removed.
+    pub(super) fn read(self: &Arc<Self>, url: &Url) -> Option<Bytes> {
+        let entry = self.get(url)?;
+        let bytes = std::fs::read(self.file_path(url)).ok();
+
+        match bytes.filter(|bytes| entry.is_intact(bytes)) {
+            Some(bytes) => Some(bytes.into()),
+            None => {
+                self.remove(url);
+                None
+            }
+        }
+    }
+
+    pub(super) fn contains(&self, url: &Url) -> bool {
+        self.index
+            .lock()
+            .unwrap()
+            .entries
+            .contains_key(&file_name(url))
+    }
+
+    /// Removes every entry and file
+    pub(super) fn clear(&self) {
+        {
+            let mut index = self.index.lock().unwrap();
+
+            for name in index.entries.keys() {
+                let _ = std::fs::remove_file(self.dir.join(name));
+            }
+
+            *index = Index::default();
+        }
+
+        self.save();
+    }
+
+    /// Updates expiry of a file after the server responded that it's not modified.
+    /// Validators sent with the response replace the stored ones.
+    pub(super) fn refresh(
+        self: &Arc<Self>,
+        url: &Url,
+        fetched: SystemTime,
+        expires: Option<SystemTime>,
+        meta: &ResponseMeta,
+    ) {
+        {
+            let mut index = self.index.lock().unwrap();
+            let Some(entry) = index.entries.get_mut(&file_name(url)) else {
+                return;
+            };
+
+            entry.fetched = fetched;
+            entry.expires = expires;
+            entry.last_access = SystemTime::now();
+
+            if !meta.validators.is_empty() {
+                entry.validators = meta.validators.clone();
+            }
+        }
+
+        self.persist();
+    }
+
+    /// Removes the entry and its file
+    pub(super) fn remove(self: &Arc<Self>, url: &Url) {
+        let name = file_name(url);
+
+        if self.index.lock().unwrap().remove(&name).is_some() {
+            let _ = std::fs::remove_file(self.dir.join(name));
+            self.persist();
+        }
+    }
+
+    /// Removes expired entries and their files. Ones that can be revalidated are kept until
+    /// pruned for size.
+    pub(super) fn remove_expired(&self, alive_time: Option<Duration>) {
+        {
+            let mut index = self.index.lock().unwrap();
+
+            let expired = index
+                .entries
+                .iter()
+                .filter(|(_, entry)| entry.is_expired(alive_time) && !entry.can_revalidate())
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>();
+
+            for name in expired {
+                index.remove(&name);
+                let _ = std::fs::remove_file(self.dir.join(name));
+            }
+        }
+
+        self.save();
+    }
+
+    pub(super) fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    /// Writes the index on the blocking thread pool
+    pub(super) fn persist(self: &Arc<Self>) {
+        let cache = Arc::clone(self);
+        blocking::unblock(move || cache.save()).detach();
+    }
+
+    /// Writes the index to a temporary file and renames it over the old one, so that a crash
+    /// never leaves a half written index behind
+    fn save(&self) {
+        let _save_lock = self.save_lock.lock().unwrap();
+        let text = self.index.lock().unwrap().serialize();
+
+        let tmp = self.dir.join(format!("{INDEX_FILE}.tmp"));
+        let result = std::fs::write(&tmp, text)
+            .and_then(|()| std::fs::rename(&tmp, self.dir.join(INDEX_FILE)));
+
+        if let Err(e) = result {
+            eprintln!("Failed to save disk cache index: {e}");
+        }
+    }
+
+    /// Removes least recently used files until within the size limit
+    fn prune(&self, index: &mut Index) {
+        let Some(max_bytes) = self.max_bytes else {
+            return;
+        };
+
+        if index.total <= max_bytes {
+            return;
+        }
+
+        let mut entries = index
+            .entries
+            .iter()
+            .map(|(name, entry)| (name.clone(), entry.last_access))
+            .collect::<Vec<_>>();
+
+        entries.sort_unstable_by_key(|(_, last_access)| *last_access);
+
+        for (name, _) in entries {
+            if index.total <= max_bytes {
+                break;
+            }
+
+            index.remove(&name);
+            let _ = std::fs::remove_file(self.dir.join(name));
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for DiskCache {
+    /// Access times are only kept in memory until something else changes the index
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+impl Index {
+    fn insert(&mut self, name: String, entry: DiskEntry) {
+        self.total += entry.size;
+        if let Some(old) = self.entries.insert(name, entry) {
+            self.total -= old.size;
+        }
+    }
+
+    fn remove(&mut self, name: &str) -> Option<DiskEntry> {
+        let entry = self.entries.remove(name)?;
+        self.total -= entry.size;
+        Some(entry)
+    }
+
+    /// Adds files missing from the index and drops entries whose file is gone. Files that
+    /// aren't named like cache files are left alone, as they aren't ours to remove.
+    fn reconcile(&mut self, dir: &Path) -> io::Result<()> {
+        let mut files = HashMap::new();
+
+        for entry in std::fs::read_dir(dir)?.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+
+            // Left behind by a crash while writing
+            if is_temp_file(&name) {
+                let _ = std::fs::remove_file(entry.path());
+                continue;
+            }
+
+            if !is_cache_file(&name) {
+                continue;
+            }
+
+            if let Ok(metadata) = entry.metadata() {
+                if metadata.is_file() {
+                    files.insert(name, metadata);
+                }
+            }
+        }
+
+        let stale = self
+            .entries
+            .keys()
+            .filter(|name| !files.contains_key(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in stale {
+            self.remove(&name);
+        }
+
+        for (name, metadata) in files {
+            if self.entries.contains_key(&name) {
+                continue;
+            }
+
+            let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+
+            self.insert(
+                name,
+                DiskEntry {
+                    url: None,
+                    size: metadata.len(),
+                    checksum: None,
+                    fetched: modified,
+                    last_access: metadata.accessed().unwrap_or(modified),
+                    expires: None,
+                    content_type: None,
+                    validators: Validators::default(),
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// One entry per line, fields separated by tabs. Missing values are empty.
+    fn serialize(&self) -> String {
+        let mut text = format!("{INDEX_HEADER}\n");
+
+        for (name, entry) in &self.entries {
+            let _ = writeln!(
+                text,
+                "{name}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                entry.size,
+                entry.checksum.map(|c| c.to_string()).unwrap_or_default(),
+                to_secs(entry.fetched),
+                to_secs(entry.last_access),
+                entry
+                    .expires
+                    .map(to_secs)
+                    .map(|s| s.to_string())
+                    .unwrap_or_default(),
+                entry.content_type.as_deref().unwrap_or_default(),
+                entry.validators.etag.as_deref().unwrap_or_default(),
+                entry
+                    .validators
+                    .last_modified
+                    .as_deref()
+                    .unwrap_or_default(),
+                entry.url.as_deref().unwrap_or_default(),
+            );
+        }
+
+        text
+    }
+
+    /// Returns `None` if any part of the index is malformed
+    fn parse(text: &str) -> Option<Self> {
+        let mut lines = text.lines();
+
+        if lines.next()? != INDEX_HEADER {
+            return None;
+        }
+
+        let mut index = Index::default();
+
+        for line in lines {
+            let mut fields = line.split('\t');
+            let mut next = || fields.next();
+
+            let name = next()?.to_string();
+            let size = next()?.parse().ok()?;
+            let checksum = optional(next()?).map(str::parse).transpose().ok()?;
+            let fetched = from_secs(next()?.parse().ok()?);
+            let last_access = from_secs(next()?.parse().ok()?);
+            let expires = optional(next()?)
+                .map(|s| s.parse().map(from_secs))
+                .transpose()
+                .ok()?;
+            let content_type = optional(next()?).map(str::to_string);
+            let etag = optional(next()?).map(str::to_string);
+            let last_modified = optional(next()?).map(str::to_string);
+            let url = optional(next()?).map(str::to_string);
+
+            if name.is_empty() || next().is_some() {
+                return None;
+            }
+
+            index.insert(
+                name,
+                DiskEntry {
+                    url,
+                    size,
+                    checksum,
+                    fetched,
+                    last_access,
+                    expires,
+                    content_type,
+                    validators: Validators {
+                        etag,
+                        last_modified,
+                    },
+                },
+            );
+        }
+
+        Some(index)
+    }
+}
+
+/// Writes to a temporary file that is renamed over `path`, so that a crash or concurrent
+/// write never leaves a truncated file behind
+pub(super) fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = temp_path(path);
+
+    let result = std::fs::write(&tmp, bytes).and_then(|()| std::fs::rename(&tmp, path));
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+/// Unique path next to `path` to write to before renaming
+pub(super) fn temp_path(path: &Path) -> PathBuf {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    path.with_extension(format!("{id}.{TEMP_EXTENSION}"))
+}
+
+/// Same naming as before the index existed, so old cache directories stay valid
+fn file_name(url: &Url) -> String {
+    xxh3_64(url.as_str().as_bytes()).to_string()
+}
+
+/// Whether the name is one given by [`file_name`]
+fn is_cache_file(name: &str) -> bool {
+    name.parse::<u64>()
+        .is_ok_and(|hash| hash.to_string() == name)
+}
+
+/// Whether the name is one given by [`temp_path`] to a cache file
+fn is_temp_file(name: &str) -> bool {
+    name.strip_suffix(TEMP_EXTENSION)
+        .and_then(|name| name.strip_suffix('.'))
+        .and_then(|name| name.split_once('.'))
+        .is_some_and(|(file, id)| is_cache_file(file) && id.parse::<u64>().is_ok())
+}
+
+fn optional(field: &str) -> Option<&str> {
+    (!field.is_empty()).then_some(field)
+}
+
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn from_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(size: u64, last_access: u64) -> DiskEntry {
+        DiskEntry {
+            url: None,
+            size,
+            checksum: None,
+            fetched: from_secs(1_000),
+            last_access: from_secs(last_access),
+            expires: None,
+            content_type: None,
+            validators: Validators::default(),
+        }
+    }
+
+    fn write_index(dir: &Path, entries: &[(&str, DiskEntry)]) {
+        let mut index = Index::default();
+        for (name, entry) in entries {
+            std::fs::write(dir.join(name), vec![0; entry.size as usize]).unwrap();
+            index.insert((*name).to_string(), entry.clone());
+        }
+        std::fs::write(dir.join(INDEX_FILE), index.serialize()).unwrap();
+    }
+
+    #[test]
+    fn index_round_trips() {
+        let mut index = Index::default();
+        index.insert(
+            "1".to_string(),
+            DiskEntry {
+                url: Some("https://example.com/a.png?size=2".to_string()),
+                checksum: Some(u64::MAX),
+                expires: Some(from_secs(2_000)),
+                content_type: Some("image/png".to_string()),
+                validators: Validators {
+                    etag: Some("\"abc\"".to_string()),
+                    last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+                },
+                ..entry(10, 1_500)
+            },
+        );
+        index.insert("2".to_string(), entry(5, 1_200));
+
+        let text = index.serialize();
+        let parsed = Index::parse(&text).unwrap();
+
+        assert_eq!(parsed.total, 15);
+        assert_eq!(parsed.serialize().lines().count(), 3);
+
+        let full = &parsed.entries["1"];
+        assert_eq!(
+            full.url.as_deref(),
+            Some("https://example.com/a.png?size=2")
+        );
+        assert_eq!(full.size, 10);
+        assert_eq!(full.checksum, Some(u64::MAX));
+        assert_eq!(full.fetched, from_secs(1_000));
+        assert_eq!(full.last_access, from_secs(1_500));
+        assert_eq!(full.expires, Some(from_secs(2_000)));
+        assert_eq!(full.content_type.as_deref(), Some("image/png"));
+        assert_eq!(full.validators.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(
+            full.validators.last_modified.as_deref(),
+            Some("Wed, 21 Oct 2015 07:28:00 GMT")
+        );
+
+        let minimal = &parsed.entries["2"];
+        assert!(minimal.url.is_none());
+        assert!(minimal.checksum.is_none());
+        assert!(minimal.expires.is_none());
+        assert!(minimal.content_type.is_none());
+        assert!(minimal.validators.is_empty());
+    }
+
+    #[test]
+    fn corrupt_index_is_rejected() {
+        let line = "1\t10\t\t1000\t1500\t\t\t\t\t";
+        assert!(Index::parse(&format!("{INDEX_HEADER}\n{line}\n")).is_some());
+
+        for text in [
+            String::new(),
+            format!("{line}\n"),
+            format!("floem-things-cache-index 2\n{line}\n"),
+            format!("{INDEX_HEADER}\n1\tten\t\t1000\t1500\t\t\t\t\t\n"),
+            format!("{INDEX_HEADER}\n1\t10\t\t1000\n"),
+            format!("{INDEX_HEADER}\n{line}\textra\n"),
+            format!("{INDEX_HEADER}\n\t10\t\t1000\t1500\t\t\t\t\t\n"),
+        ] {
+            assert!(Index::parse(&text).is_none(), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn corrupt_index_is_rebuilt_from_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("1"), [0; 4]).unwrap();
+        std::fs::write(dir.path().join(INDEX_FILE), "not an index").unwrap();
+
+        let cache = DiskCache::open(dir.path().to_path_buf(), None).unwrap();
+        let index = cache.index.lock().unwrap();
+
+        assert_eq!(index.entries.len(), 1);
+        assert!(index.entries["1"].url.is_none());
+        assert_eq!(index.total, 4);
+    }
+
+    #[test]
+    fn reconcile_matches_index_to_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("1"), [0; 4]).unwrap();
+        std::fs::write(dir.path().join("2"), [0; 6]).unwrap();
+        std::fs::write(dir.path().join("3.0.tmp"), [0; 8]).unwrap();
+        std::fs::write(dir.path().join(INDEX_FILE), "").unwrap();
+
+        let mut index = Index::default();
+        index.insert(
+            "1".to_string(),
+            DiskEntry {
+                url: Some("https://example.com/1.png".to_string()),
+                ..entry(4, 1_500)
+            },
+        );
+        index.insert("missing".to_string(), entry(100, 1_500));
+
+        index.reconcile(dir.path()).unwrap();
+
+        let mut names = index.entries.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["1", "2"]);
+        assert_eq!(index.total, 10);
+        assert!(index.entries["1"].url.is_some());
+        assert!(index.entries["2"].url.is_none());
+        assert!(!dir.path().join("3.0.tmp").exists());
+        assert!(dir.path().join(INDEX_FILE).exists());
+    }
+
+    #[test]
+    fn prune_removes_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        write_index(
+            dir.path(),
+            &[
+                ("1", entry(10, 1_000)),
+                ("3", entry(10, 3_000)),
+                ("2", entry(10, 2_000)),
+            ],
+        );
+
+        let cache = DiskCache::open(dir.path().to_path_buf(), Some(20)).unwrap();
+
+        {
+            let index = cache.index.lock().unwrap();
+            assert_eq!(index.total, 20);
+            assert!(!index.entries.contains_key("1"));
+            assert!(index.entries.contains_key("2"));
+            assert!(index.entries.contains_key("3"));
+        }
+        assert!(!dir.path().join("1").exists());
+        assert_eq!(cache.evictions(), 1);
+
+        cache.prune(&mut cache.index.lock().unwrap());
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    #[test]
+    fn foreign_files_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        write_index(dir.path(), &[("1", entry(10, 1_000))]);
+        for name in ["my-notes.txt", "notes.tmp", "01", "+2", "3.x.tmp"] {
+            std::fs::write(dir.path().join(name), [0; 100]).unwrap();
+        }
+
+        let cache = DiskCache::open(dir.path().to_path_buf(), Some(10)).unwrap();
+        assert_eq!(cache.index.lock().unwrap().total, 10);
+        assert_eq!(cache.evictions(), 0);
+
+        cache.clear();
+        assert!(!dir.path().join("1").exists());
+        for name in ["my-notes.txt", "notes.tmp", "01", "+2", "3.x.tmp"] {
+            assert!(dir.path().join(name).exists(), "{name}");
+        }
+    }
+
+    #[test]
+    fn prune_keeps_everything_without_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        write_index(
+            dir.path(),
+            &[("1", entry(10, 1_000)), ("2", entry(10, 2_000))],
+        );
+
+        let cache = DiskCache::open(dir.path().to_path_buf(), None).unwrap();
+
+        assert_eq!(cache.index.lock().unwrap().total, 20);
+        assert_eq!(cache.evictions(), 0);
+    }
+}
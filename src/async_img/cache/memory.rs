@@ -0,0 +1,233 @@
+use std::{
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::SystemTime,
+};
+
+use bytes::Bytes;
+use dashmap::DashMap;
+use reqwest::Url;
+use xxhash_rust::xxh3::Xxh3Builder;
+
+/// In-memory tier of the cache. Keeps encoded images and evicts the least recently used ones
+/// when over the configured limits.
+pub(super) struct MemoryCache {
+    map: DashMap<Url, MemoryEntry, Xxh3Builder>,
+    /// Total size of the images in the map
+    used: AtomicUsize,
+    /// Incremented on every access, orders entries by recency
+    clock: AtomicU64,
+    max_bytes: Option<usize>,
+    max_entries: Option<usize>,
+    /// Entries removed by [`MemoryCache::evict`]
+    evictions: AtomicU64,
+}
+
+pub(super) struct MemoryEntry {
+    pub(super) bytes: Bytes,
+    /// When the image has to be revalidated, same as in the disk cache index
+    pub(super) expires: Option<SystemTime>,
+    last_access: AtomicU64,
+}
+
+impl MemoryCache {
+    pub(super) fn new(max_bytes: Option<usize>, max_entries: Option<usize>) -> Self {
+        Self {
+            map: DashMap::with_hasher(Xxh3Builder::new()),
+            used: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+            max_bytes,
+            max_entries,
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the image and its expiry, marking it as recently used
+    pub(super) fn get(&self, url: &Url) -> Option<(Bytes, Option<SystemTime>)> {
+        self.map.get(url).map(|entry| {
+            entry.last_access.store(self.tick(), Ordering::Relaxed);
+            (entry.bytes.clone(), entry.expires)
+        })
+    }
+
+    /// Images larger than `max_bytes` are not kept, so that they don't evict everything else.
+    /// They replace an older image for the url all the same.
+    pub(super) fn insert(&self, url: Url, bytes: Bytes, expires: Option<SystemTime>) {
+        let size = bytes.len();
+
+        if self.max_bytes.is_some_and(|max| size > max) {
+            self.remove(&url);
+            return;
+        }
+
+        let entry = MemoryEntry {
+            bytes,
+            expires,
+            last_access: AtomicU64::new(self.tick()),
+        };
+
+        self.used.fetch_add(size, Ordering::Relaxed);
+        if let Some(old) = self.map.insert(url, entry) {
+            self.used.fetch_sub(old.bytes.len(), Ordering::Relaxed);
+        }
+
+        self.evict();
+    }
+
+    pub(super) fn remove(&self, url: &Url) {
+        if let Some((_, old)) = self.map.remove(url) {
+            self.used.fetch_sub(old.bytes.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Doesn't count as access
+    pub(super) fn contains(&self, url: &Url) -> bool {
+        self.map.contains_key(url)
+    }
+
+    pub(super) fn clear(&self) {
+        self.retain(|_, _| false);
+    }
+
+    pub(super) fn retain(&self, mut f: impl FnMut(&Url, &MemoryEntry) -> bool) {
+        self.map.retain(|url, entry| {
+            let keep = f(url, entry);
+            if !keep {
+                self.used.fetch_sub(entry.bytes.len(), Ordering::Relaxed);
+            }
+            keep
+        });
+    }
+
+    pub(super) fn evictions(&self) -> u64 {
+        self.evictions.load(Ordering::Relaxed)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn is_over_limit(&self) -> bool {
+        self.max_bytes
+            .is_some_and(|max| self.used.load(Ordering::Relaxed) > max)
+            || self.max_entries.is_some_and(|max| self.map.len() > max)
+    }
+
+    /// Removes least recently used entries until within limits. Entries written to disk
+    /// can still be read from there.
+    fn evict(&self) {
+        if !self.is_over_limit() {
+            return;
+        }
+
+        let mut entries = self
+            .map
+            .iter()
+            .map(|entry| {
+                let last_access = entry.last_access.load(Ordering::Relaxed);
+                (entry.key().clone(), last_access)
+            })
+            .collect::<Vec<_>>();
+
+        entries.sort_unstable_by_key(|(_, last_access)| *last_access);
+
+        for (url, _) in entries {
+            if !self.is_over_limit() {
+                break;
+            }
+            self.remove(&url);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(i: usize) -> Url {
+        Url::parse(&format!("https://example.com/{i}.png")).unwrap()
+    }
+
+    fn insert(cache: &MemoryCache, i: usize, size: usize) {
+        cache.insert(url(i), vec![0; size].into(), None);
+    }
+
+    fn kept(cache: &MemoryCache, count: usize) -> Vec<usize> {
+        (0..count).filter(|&i| cache.contains(&url(i))).collect()
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted_first() {
+        let cache = MemoryCache::new(None, Some(3));
+        for i in 0..3 {
+            insert(&cache, i, 10);
+        }
+
+        // Accessed, so 1 is the least recently used
+        cache.get(&url(0));
+        insert(&cache, 3, 10);
+
+        assert_eq!(kept(&cache, 4), [0, 2, 3]);
+        assert_eq!(cache.evictions(), 1);
+    }
+
+    #[test]
+    fn byte_limit_is_kept() {
+        let cache = MemoryCache::new(Some(100), None);
+        for i in 0..5 {
+            insert(&cache, i, 30);
+        }
+
+        assert_eq!(kept(&cache, 5), [2, 3, 4]);
+        assert_eq!(cache.used.load(Ordering::Relaxed), 90);
+        assert_eq!(cache.evictions(), 2);
+
+        // Replacing an entry counts only the new size
+        insert(&cache, 4, 10);
+        assert_eq!(cache.used.load(Ordering::Relaxed), 70);
+    }
+
+    #[test]
+    fn entry_limit_is_kept() {
+        let cache = MemoryCache::new(None, Some(2));
+        for i in 0..5 {
+            insert(&cache, i, 1);
+        }
+
+        assert_eq!(kept(&cache, 5), [3, 4]);
+        assert_eq!(cache.evictions(), 3);
+    }
+
+    #[test]
+    fn oversized_entry_is_not_kept() {
+        let cache = MemoryCache::new(Some(100), None);
+        for i in 0..5 {
+            insert(&cache, i, 10);
+        }
+
+        insert(&cache, 5, 200);
+        assert_eq!(kept(&cache, 6), [0, 1, 2, 3, 4]);
+        assert_eq!(cache.used.load(Ordering::Relaxed), 50);
+        assert_eq!(cache.evictions(), 0);
+
+        // Doesn't leave an older image for the url behind
+        insert(&cache, 0, 200);
+        assert_eq!(kept(&cache, 6), [1, 2, 3, 4]);
+        assert_eq!(cache.used.load(Ordering::Relaxed), 40);
+    }
+
+    #[test]
+    fn remove_and_clear_free_space() {
+        let cache = MemoryCache::new(None, None);
+        for i in 0..3 {
+            insert(&cache, i, 10);
+        }
+
+        cache.remove(&url(1));
+        assert_eq!(cache.used.load(Ordering::Relaxed), 20);
+
+        cache.clear();
+        assert_eq!(kept(&cache, 3), []);
+        assert_eq!(cache.used.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Counters of an [`AsyncCache`](super::AsyncCache) since it was created
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Views that got a fresh image from memory
+    pub memory_hits: u64,
+    pub memory_misses: u64,
+    /// Fetches served from `local_cache_path`, including ones the server said are not modified
+    /// or that couldn't be revalidated
+    pub disk_hits: u64,
+    pub disk_misses: u64,
+    /// Images downloaded in full
+    pub network_fetches: u64,
+    /// Failed downloads, including revalidations
+    pub network_failures: u64,
+    pub bytes_downloaded: u64,
+    /// Images evicted from memory to stay within the limits
+    pub memory_evictions: u64,
+    /// Files removed to stay within `max_disk_bytes`
+    pub disk_evictions: u64,
+    /// Fetches currently running
+    pub in_flight: usize,
+    /// Average time a fetch took to get the image from disk or network
+    pub average_fetch_latency: Option<Duration>,
+}
+
+#[derive(Default)]
+pub(super) struct Stats {
+    pub(super) memory_hits: AtomicU64,
+    pub(super) memory_misses: AtomicU64,
+    pub(super) disk_hits: AtomicU64,
+    pub(super) disk_misses: AtomicU64,
+    pub(super) network_fetches: AtomicU64,
+    pub(super) network_failures: AtomicU64,
+    pub(super) bytes_downloaded: AtomicU64,
+    fetches: AtomicU64,
+    fetch_micros: AtomicU64,
+}
+
+impl Stats {
+    pub(super) fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub(super) fn record_latency(&self, elapsed: Duration) {
+        Self::add(&self.fetches, 1);
+        Self::add(&self.fetch_micros, elapsed.as_micros() as u64);
+    }
+
+    /// Counters kept elsewhere are filled in by the caller
+    pub(super) fn snapshot(&self) -> CacheStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let fetches = load(&self.fetches);
+        let average_fetch_latency =
+            (fetches > 0).then(|| Duration::from_micros(load(&self.fetch_micros) / fetches));
+
+        CacheStats {
+            memory_hits: load(&self.memory_hits),
+            memory_misses: load(&self.memory_misses),
+            disk_hits: load(&self.disk_hits),
+            disk_misses: load(&self.disk_misses),
+            network_fetches: load(&self.network_fetches),
+            network_failures: load(&self.network_failures),
+            bytes_downloaded: load(&self.bytes_downloaded),
+            average_fetch_latency,
+            ..CacheStats::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_is_averaged_over_fetches() {
+        let stats = Stats::default();
+        assert_eq!(stats.snapshot().average_fetch_latency, None);
+
+        stats.record_latency(Duration::from_millis(10));
+        stats.record_latency(Duration::from_millis(30));
+        assert_eq!(
+            stats.snapshot().average_fetch_latency,
+            Some(Duration::from_millis(20))
+        );
+    }
+
+    #[test]
+    fn snapshot_has_the_counters() {
+        let stats = Stats::default();
+        Stats::add(&stats.memory_hits, 3);
+        Stats::add(&stats.disk_misses, 2);
+        Stats::add(&stats.bytes_downloaded, 100);
+
+        assert_eq!(
+            stats.snapshot(),
+            CacheStats {
+                memory_hits: 3,
+                disk_misses: 2,
+                bytes_downloaded: 100,
+                ..CacheStats::default()
+            }
+        );
+    }
+}
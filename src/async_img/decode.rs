@@ -0,0 +1,157 @@
+use std::{io::Cursor, time::Duration};
+
+use bytes::Bytes;
+use image::{
+    codecs::{gif::GifDecoder, png::PngDecoder, webp::WebPDecoder},
+    imageops::FilterType,
+    AnimationDecoder, DynamicImage, Frames, ImageFormat,
+};
+
+use super::{
+    animation::{Animation, AnimationFrame},
+    error::FetchError,
+    FetchEvent, FetchOptions, Handoff,
+};
+
+/// How the image is fitted into the size given with [`AsyncImage::downscale`], or into the
+/// view with [`AsyncImage::object_fit`]
+///
+/// [`AsyncImage::downscale`]: super::AsyncImage::downscale
+/// [`AsyncImage::object_fit`]: super::AsyncImage::object_fit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fit {
+    /// Keep aspect ratio, whole image fits inside the size
+    #[default]
+    Contain,
+    /// Keep aspect ratio, image covers the size and overflow is cropped
+    Cover,
+    /// Stretch to exactly the size
+    Fill,
+    /// Keep original size, cropped if it's larger. Not downscaled.
+    None,
+    /// Same as `Contain` but never enlarges the image
+    ScaleDown,
+}
+
+/// Image decoded for the view. Cloning copies the pixels.
+#[derive(Clone)]
+pub(crate) enum Decoded {
+    Still(DynamicImage),
+    Animated(Animation),
+}
+
+impl From<Decoded> for FetchEvent {
+    fn from(decoded: Decoded) -> Self {
+        match decoded {
+            Decoded::Still(image) => FetchEvent::Image(Handoff::new(image)),
+            Decoded::Animated(animation) => FetchEvent::Animation(Handoff::new(animation)),
+        }
+    }
+}
+
+/// Decodes every frame of an animation if the options ask for it, otherwise the first one
+pub(crate) fn decode(bytes: &[u8], options: FetchOptions) -> Result<Decoded, FetchError> {
+    if options.animated {
+        if let Some(frames) = decode_frames(bytes, options)?.filter(|f| f.len() > 1) {
+            return Ok(Decoded::Animated(Animation::new(frames)));
+        }
+    }
+
+    decode_still(bytes, options).map(Decoded::Still)
+}
+
+/// Decodes the image, downscales it if the options ask for it, and converts the pixels to
+/// RGBA, which the view shows as they are
+pub(crate) fn decode_still(
+    bytes: &[u8],
+    options: FetchOptions,
+) -> Result<DynamicImage, FetchError> {
+    let image = image::load_from_memory(bytes).map_err(FetchError::Decode)?;
+    Ok(rgba(resize(image, options)))
+}
+
+/// Frames of a GIF, APNG or WebP animation, composed and converted like still images. `None`
+/// if the image isn't animated.
+fn decode_frames(
+    bytes: &[u8],
+    options: FetchOptions,
+) -> Result<Option<Vec<AnimationFrame>>, FetchError> {
+    let Some(frames) = animation_frames(bytes).map_err(FetchError::Decode)? else {
+        return Ok(None);
+    };
+
+    frames
+        .map(|frame| {
+            let frame = frame.map_err(FetchError::Decode)?;
+            let delay = Duration::from(frame.delay());
+            let image = resize(DynamicImage::ImageRgba8(frame.into_buffer()), options);
+
+            Ok(AnimationFrame {
+                image: rgba(image),
+                delay,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn animation_frames(bytes: &[u8]) -> image::ImageResult<Option<Frames<'_>>> {
+    let cursor = Cursor::new(bytes);
+
+    let frames = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => GifDecoder::new(cursor)?.into_frames(),
+        Ok(ImageFormat::Png) => {
+            let decoder = PngDecoder::new(cursor)?;
+            if !decoder.is_apng() {
+                return Ok(None);
+            }
+            decoder.apng().into_frames()
+        }
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(cursor)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            decoder.into_frames()
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(frames))
+}
+
+pub(crate) fn resize(image: DynamicImage, options: FetchOptions) -> DynamicImage {
+    match options.size {
+        Some((width, height)) => downscale(image, width, height, options.fit),
+        None => image,
+    }
+}
+
+/// Pixels in the layout the view draws
+pub(crate) fn rgba(image: DynamicImage) -> DynamicImage {
+    DynamicImage::ImageRgba8(image.into_rgba8())
+}
+
+/// Decodes on the blocking thread pool instead of the async executor
+pub(crate) async fn decode_unblocked(
+    bytes: Bytes,
+    options: FetchOptions,
+) -> Result<Decoded, FetchError> {
+    blocking::unblock(move || decode(&bytes, options)).await
+}
+
+/// Never upscales
+fn downscale(image: DynamicImage, width: u32, height: u32, fit: Fit) -> DynamicImage {
+    let (w, h) = (image.width(), image.height());
+
+    match fit {
+        Fit::Contain | Fit::ScaleDown if w > width || h > height => image.thumbnail(width, height),
+        Fit::Cover if w > width && h > height => {
+            image.resize_to_fill(width, height, FilterType::Triangle)
+        }
+        Fit::Fill if w > width || h > height => {
+            image.resize_exact(width, height, FilterType::Triangle)
+        }
+        _ => image,
+    }
+}
@@ -0,0 +1,60 @@
+use std::fmt::Display;
+
+use reqwest::StatusCode;
+
+/// Reason why an image could not be fetched or why a response was rejected
+#[derive(Debug)]
+pub enum FetchError {
+    /// Url could not be parsed
+    Url(url::ParseError),
+    /// Network or protocol error from the http client
+    Request(reqwest::Error),
+    /// Server responded with non-2xx status
+    Status(StatusCode),
+    /// Response `Content-Type` is not an image
+    ContentType(String),
+    /// Response body doesn't start with any known image signature
+    NotAnImage,
+    /// Reading the response body failed
+    Io(std::io::Error),
+    /// Image could not be decoded
+    Decode(image::ImageError),
+    /// Writing the image to the local cache failed
+    Disk(std::io::Error),
+}
+
+impl Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Url(e) => write!(f, "Invalid url: {e}"),
+            FetchError::Request(e) => write!(f, "Request failed: {e}"),
+            FetchError::Status(status) => write!(f, "Unexpected response status: {status}"),
+            FetchError::ContentType(content_type) => {
+                write!(f, "Unexpected content type: {content_type}")
+            }
+            FetchError::NotAnImage => write!(f, "Response body is not an image"),
+            FetchError::Io(e) => write!(f, "Failed to read response: {e}"),
+            FetchError::Decode(e) => write!(f, "Failed to decode image: {e}"),
+            FetchError::Disk(e) => write!(f, "Failed to write local cache: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for FetchError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FetchError::Url(e) => Some(e),
+            FetchError::Request(e) => Some(e),
+            FetchError::Io(e) => Some(e),
+            FetchError::Decode(e) => Some(e),
+            FetchError::Disk(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for FetchError {
+    fn from(value: reqwest::Error) -> Self {
+        FetchError::Request(value)
+    }
+}
@@ -0,0 +1,169 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+/// Future of a fetch, as given to [`Spawner::spawn`]
+pub type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Runs fetches in the background. Implemented for each runtime feature, and can be
+/// implemented for other executors.
+pub trait Spawner: Send + Sync + 'static {
+    /// Runs the future until it completes or the returned handle is dropped.
+    ///
+    /// The future makes requests with `reqwest`, so it must be polled within a tokio
+    /// context. Executors other than tokio can wrap it in `async_compat::Compat`.
+    fn spawn(&self, future: BoxFuture) -> TaskHandle;
+}
+
+/// Handle to a spawned fetch task. Dropping it cancels the task.
+#[must_use]
+pub struct TaskHandle(Option<Box<dyn FnOnce() + Send + Sync>>);
+
+impl TaskHandle {
+    /// `cancel` is called when the handle is dropped
+    pub fn new(cancel: impl FnOnce() + Send + Sync + 'static) -> Self {
+        Self(Some(Box::new(cancel)))
+    }
+
+    /// For tasks that can't be cancelled
+    pub fn detached() -> Self {
+        Self(None)
+    }
+}
+
+impl Drop for TaskHandle {
+    fn drop(&mut self) {
+        if let Some(cancel) = self.0.take() {
+            cancel();
+        }
+    }
+}
+
+/// Spawner used by views and caches. Views use the one provided with floems
+/// `provide_context`, and [`AsyncCache`](super::cache::AsyncCache) the one in its config.
+/// By default it's the first enabled of `tokio`, `async-std`, `smol` and `thread`.
+#[derive(Clone)]
+pub struct Executor(Arc<dyn Spawner>);
+
+impl Executor {
+    pub fn new(spawner: impl Spawner) -> Self {
+        Self(Arc::new(spawner))
+    }
+
+    pub(crate) fn spawn(&self, future: impl Future<Output = ()> + Send + 'static) -> TaskHandle {
+        self.0.spawn(Box::pin(future))
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        #[cfg(feature = "tokio")]
+        return Self::new(TokioSpawner::default());
+
+        #[cfg(all(feature = "async-std", not(feature = "tokio")))]
+        return Self::new(AsyncStdSpawner);
+
+        #[cfg(all(feature = "smol", not(any(feature = "tokio", feature = "async-std"))))]
+        return Self::new(SmolSpawner);
+
+        #[cfg(all(
+            feature = "thread",
+            not(any(feature = "tokio", feature = "async-std", feature = "smol"))
+        ))]
+        return Self::new(super::pool::ThreadPool::global());
+
+        #[allow(unreachable_code)]
+        Self::new(Unavailable)
+    }
+}
+
+/// Spawns on the given tokio runtime. By default it's the runtime the fetch is started
+/// from, or a runtime owned by this crate if there's none, as floems UI thread usually isn't
+/// within one.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Debug, Default)]
+pub struct TokioSpawner {
+    handle: Option<tokio::runtime::Handle>,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioSpawner {
+    #[must_use]
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        Self {
+            handle: Some(handle),
+        }
+    }
+
+    fn handle(&self) -> tokio::runtime::Handle {
+        static RUNTIME: std::sync::OnceLock<tokio::runtime::Runtime> = std::sync::OnceLock::new();
+
+        self.handle
+            .clone()
+            .or_else(|| tokio::runtime::Handle::try_current().ok())
+            .unwrap_or_else(|| {
+                RUNTIME
+                    .get_or_init(|| {
+                        tokio::runtime::Builder::new_multi_thread()
+                            .thread_name("floem-things-fetch")
+                            .enable_all()
+                            .build()
+                            .expect("Cannot start tokio runtime for fetching images")
+                    })
+                    .handle()
+                    .clone()
+            })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Spawner for TokioSpawner {
+    fn spawn(&self, future: BoxFuture) -> TaskHandle {
+        let handle = self.handle().spawn(future);
+        TaskHandle::new(move || handle.abort())
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl From<tokio::runtime::Handle> for Executor {
+    fn from(handle: tokio::runtime::Handle) -> Self {
+        Self::new(TokioSpawner::new(handle))
+    }
+}
+
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdSpawner;
+
+#[cfg(feature = "async-std")]
+impl Spawner for AsyncStdSpawner {
+    fn spawn(&self, future: BoxFuture) -> TaskHandle {
+        let handle = async_std::task::spawn(async_compat::Compat::new(future));
+
+        TaskHandle::new(move || {
+            async_std::task::spawn(handle.cancel());
+        })
+    }
+}
+
+/// Spawns on smol's global executor
+#[cfg(feature = "smol")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmolSpawner;
+
+#[cfg(feature = "smol")]
+impl Spawner for SmolSpawner {
+    fn spawn(&self, future: BoxFuture) -> TaskHandle {
+        // Dropping smol task cancels it
+        let task = smol::spawn(async_compat::Compat::new(future));
+        TaskHandle::new(move || drop(task))
+    }
+}
+
+/// Without any runtime feature fetches can't run until an executor is provided
+struct Unavailable;
+
+impl Spawner for Unavailable {
+    fn spawn(&self, _: BoxFuture) -> TaskHandle {
+        eprintln!("No executor for fetching images, enable a runtime feature or provide one");
+        TaskHandle::detached()
+    }
+}
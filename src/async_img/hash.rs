@@ -0,0 +1,426 @@
+use std::f32::consts::PI;
+
+use base64::{engine::general_purpose, Engine};
+use image::{DynamicImage, RgbaImage};
+
+/// Longest side in pixels of a decoded preview. The view stretches it, and the blur hides that.
+const PREVIEW_SIZE: u32 = 32;
+
+/// Compact hash of an image, decoded to a blurred preview that is shown until the image loads.
+/// See [`AsyncImage::placeholder_hash`](super::AsyncImage::placeholder_hash).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PlaceholderHash {
+    /// [BlurHash](https://blurha.sh) string. It doesn't store the aspect ratio, so the preview
+    /// takes the aspect ratio of the view.
+    BlurHash(String),
+    /// Bytes of a [ThumbHash](https://evanw.github.io/thumbhash/). The preview has the
+    /// aspect ratio of the image.
+    ThumbHash(Vec<u8>),
+}
+
+impl PlaceholderHash {
+    #[must_use]
+    pub fn blurhash(hash: impl Into<String>) -> Self {
+        Self::BlurHash(hash.into())
+    }
+
+    /// ThumbHash encoded as base64, with or without padding
+    #[must_use]
+    pub fn thumbhash_base64(hash: &str) -> Option<Self> {
+        general_purpose::STANDARD
+            .decode(hash)
+            .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(hash))
+            .ok()
+            .map(Self::ThumbHash)
+    }
+
+    /// Whether the preview depends on the aspect ratio of the view
+    pub(crate) fn follows_view(&self) -> bool {
+        matches!(self, Self::BlurHash(_))
+    }
+
+    /// Decodes the preview for a view with the given width divided by height. `None` if the
+    /// hash is invalid.
+    pub(crate) fn preview(&self, ratio: f64) -> Option<DynamicImage> {
+        let image = match self {
+            Self::BlurHash(hash) => {
+                let (width, height) = preview_size(ratio);
+                blurhash(hash, width, height)?
+            }
+            Self::ThumbHash(hash) => thumbhash(hash)?,
+        };
+
+        Some(DynamicImage::ImageRgba8(image))
+    }
+}
+
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+fn preview_size(ratio: f64) -> (u32, u32) {
+    let size = f64::from(PREVIEW_SIZE);
+
+    if !ratio.is_finite() || ratio <= 0.0 {
+        (PREVIEW_SIZE, PREVIEW_SIZE)
+    } else if ratio > 1.0 {
+        (PREVIEW_SIZE, (size / ratio).round().max(1.0) as u32)
+    } else {
+        ((size * ratio).round().max(1.0) as u32, PREVIEW_SIZE)
+    }
+}
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn base83(chars: &[u8]) -> Option<u32> {
+    chars.iter().try_fold(0, |value, &c| {
+        let digit = BASE83.iter().position(|&b| b == c)?;
+        Some(value * 83 + u32::try_from(digit).ok()?)
+    })
+}
+
+/// Decodes a BlurHash to an image of the given size
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+fn blurhash(hash: &str, width: u32, height: u32) -> Option<RgbaImage> {
+    let hash = hash.as_bytes();
+
+    let size_flag = base83(hash.get(..1)?)?;
+    let (nx, ny) = (size_flag % 9 + 1, size_flag / 9 + 1);
+    if hash.len() != 4 + 2 * (nx * ny) as usize {
+        return None;
+    }
+
+    let max_value = (base83(&hash[1..2])? + 1) as f32 / 166.0;
+
+    let dc = base83(&hash[2..6])?;
+    let mut colors = vec![[
+        srgb_to_linear(dc >> 16),
+        srgb_to_linear((dc >> 8) & 255),
+        srgb_to_linear(dc & 255),
+    ]];
+
+    for chunk in hash[6..].chunks(2) {
+        let value = base83(chunk)?;
+        let ac = |quantized: u32| {
+            let v = (quantized as f32 - 9.0) / 9.0;
+            v.signum() * v * v * max_value
+        };
+        colors.push([ac(value / (19 * 19)), ac((value / 19) % 19), ac(value % 19)]);
+    }
+
+    Some(RgbaImage::from_fn(width, height, |x, y| {
+        let mut pixel = [0.0; 3];
+
+        for j in 0..ny {
+            for i in 0..nx {
+                let basis = (PI * x as f32 * i as f32 / width as f32).cos()
+                    * (PI * y as f32 * j as f32 / height as f32).cos();
+                let color = colors[(i + j * nx) as usize];
+
+                for (p, c) in pixel.iter_mut().zip(color) {
+                    *p += c * basis;
+                }
+            }
+        }
+
+        let [r, g, b] = pixel.map(linear_to_srgb);
+        image::Rgba([r, g, b, 255])
+    }))
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn srgb_to_linear(value: u32) -> f32 {
+    let v = value as f32 / 255.0;
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn linear_to_srgb(value: f32) -> u8 {
+    let v = value.clamp(0.0, 1.0);
+    let srgb = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0 + 0.5) as u8
+}
+
+/// Decodes a ThumbHash to an image of at most 32 pixels on the longest side, following the
+/// reference implementation
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss,
+    clippy::many_single_char_names
+)]
+fn thumbhash(hash: &[u8]) -> Option<RgbaImage> {
+    let header24 =
+        u32::from(*hash.first()?) | u32::from(*hash.get(1)?) << 8 | u32::from(*hash.get(2)?) << 16;
+    let header16 = u16::from(*hash.get(3)?) | u16::from(*hash.get(4)?) << 8;
+
+    let l_dc = (header24 & 63) as f32 / 63.0;
+    let p_dc = ((header24 >> 6) & 63) as f32 / 31.5 - 1.0;
+    let q_dc = ((header24 >> 12) & 63) as f32 / 31.5 - 1.0;
+    let l_scale = ((header24 >> 18) & 31) as f32 / 31.0;
+    let has_alpha = header24 >> 23 != 0;
+    let p_scale = f32::from((header16 >> 3) & 63) / 63.0;
+    let q_scale = f32::from((header16 >> 9) & 63) / 63.0;
+    let is_landscape = header16 >> 15 != 0;
+
+    let short = if has_alpha { 5 } else { 7 };
+    let long = usize::from(header16 & 7);
+    let (lx, ly) = if is_landscape {
+        (short, long)
+    } else {
+        (long, short)
+    };
+    let ratio = lx as f32 / ly as f32;
+    let (lx, ly) = (lx.max(3), ly.max(3));
+
+    let (a_dc, a_scale) = if has_alpha {
+        let byte = *hash.get(5)?;
+        (f32::from(byte & 15) / 15.0, f32::from(byte >> 4) / 15.0)
+    } else {
+        (1.0, 0.0)
+    };
+
+    // AC coefficients are packed as nibbles after the header
+    let mut nibbles = (if has_alpha { 6 } else { 5 }..)
+        .flat_map(|i| [(i, 0), (i, 4)])
+        .map(|(i, shift)| hash.get(i).map(|byte| (byte >> shift) & 15));
+    let mut channel = |nx: usize, ny: usize, scale: f32| -> Option<Vec<f32>> {
+        let mut ac = Vec::new();
+        for cy in 0..ny {
+            let mut cx = usize::from(cy == 0);
+            while cx * ny < nx * (ny - cy) {
+                ac.push((f32::from(nibbles.next()??) / 7.5 - 1.0) * scale);
+                cx += 1;
+            }
+        }
+        Some(ac)
+    };
+
+    let l_ac = channel(lx, ly, l_scale)?;
+    let p_ac = channel(3, 3, p_scale * 1.25)?;
+    let q_ac = channel(3, 3, q_scale * 1.25)?;
+    let a_ac = if has_alpha {
+        channel(5, 5, a_scale)?
+    } else {
+        Vec::new()
+    };
+
+    let size = PREVIEW_SIZE as f32;
+    let (width, height) = if ratio > 1.0 {
+        (size, (size / ratio).round())
+    } else {
+        ((size * ratio).round(), size)
+    };
+    let (width, height) = (width.max(1.0) as u32, height.max(1.0) as u32);
+
+    let n = lx.max(if has_alpha { 5 } else { 3 });
+    let m = ly.max(if has_alpha { 5 } else { 3 });
+
+    Some(RgbaImage::from_fn(width, height, |x, y| {
+        let fx: Vec<f32> = (0..n)
+            .map(|cx| (PI / width as f32 * (x as f32 + 0.5) * cx as f32).cos())
+            .collect();
+        let fy: Vec<f32> = (0..m)
+            .map(|cy| (PI / height as f32 * (y as f32 + 0.5) * cy as f32).cos())
+            .collect();
+
+        // Sums the coefficients of a channel over the triangle of frequencies it stores
+        let sum = |ac: &[f32], nx: usize, ny: usize| {
+            let mut value = 0.0;
+            let mut j = 0;
+            for (cy, fy) in fy.iter().enumerate().take(ny) {
+                let mut cx = usize::from(cy == 0);
+                while cx * ny < nx * (ny - cy) {
+                    value += ac[j] * fx[cx] * fy * 2.0;
+                    cx += 1;
+                    j += 1;
+                }
+            }
+            value
+        };
+
+        let l = l_dc + sum(&l_ac, lx, ly);
+        let p = p_dc + sum(&p_ac, 3, 3);
+        let q = q_dc + sum(&q_ac, 3, 3);
+        let a = if has_alpha {
+            a_dc + sum(&a_ac, 5, 5)
+        } else {
+            a_dc
+        };
+
+        let b = l - 2.0 / 3.0 * p;
+        let r = (3.0 * l - b + q) / 2.0;
+        let g = r - q;
+
+        let [r, g, b, a] = [r, g, b, a].map(|v| (v.clamp(0.0, 1.0) * 255.0) as u8);
+        image::Rgba([r, g, b, a])
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pixels decoded by the reference implementations, which compute in `f64`. Rounding may
+    /// differ by one.
+    fn assert_pixels(image: &RgbaImage, expected: &[((u32, u32), [u8; 4])]) {
+        for &((x, y), expected) in expected {
+            let pixel = image.get_pixel(x, y).0;
+            let close = pixel.iter().zip(expected).all(|(&a, b)| a.abs_diff(b) <= 1);
+            assert!(close, "({x}, {y}): {pixel:?} != {expected:?}");
+        }
+    }
+
+    fn thumbhash_bytes(hash: &str) -> Vec<u8> {
+        match PlaceholderHash::thumbhash_base64(hash) {
+            Some(PlaceholderHash::ThumbHash(bytes)) => bytes,
+            _ => panic!("invalid base64 {hash}"),
+        }
+    }
+
+    #[test]
+    fn blurhash_matches_reference() {
+        let image = blurhash("LEHV6nWB2yk8pyo0adR*.7kCMdnj", 32, 21).unwrap();
+
+        assert_eq!(image.dimensions(), (32, 21));
+        assert_pixels(
+            &image,
+            &[
+                ((0, 0), [135, 164, 177, 255]),
+                ((31, 0), [137, 166, 181, 255]),
+                ((0, 20), [136, 144, 147, 255]),
+                ((31, 20), [132, 142, 147, 255]),
+                ((16, 10), [158, 127, 110, 255]),
+            ],
+        );
+    }
+
+    #[test]
+    fn blurhash_preview_follows_view_ratio() {
+        assert_eq!(preview_size(1.5), (32, 21));
+        assert_eq!(preview_size(0.5), (16, 32));
+        assert_eq!(preview_size(1.0), (32, 32));
+        assert_eq!(preview_size(1000.0), (32, 1));
+        assert_eq!(preview_size(0.0), (32, 32));
+        assert_eq!(preview_size(f64::NAN), (32, 32));
+    }
+
+    #[test]
+    fn thumbhash_matches_reference() {
+        let portrait = thumbhash(&thumbhash_bytes("1QcSHQRnh493V4dIh4eXh1h4kJUI")).unwrap();
+        assert_eq!(portrait.dimensions(), (23, 32));
+        assert_pixels(
+            &portrait,
+            &[
+                ((0, 0), [64, 77, 113, 255]),
+                ((22, 0), [84, 109, 139, 255]),
+                ((0, 31), [0, 12, 42, 255]),
+                ((22, 31), [0, 4, 39, 255]),
+                ((11, 16), [140, 109, 88, 255]),
+            ],
+        );
+
+        let landscape = thumbhash(&thumbhash_bytes("3OcRJYB4d3h/iIeHeEh3eIhw+j3A")).unwrap();
+        assert_eq!(landscape.dimensions(), (32, 23));
+        assert_pixels(
+            &landscape,
+            &[
+                ((0, 0), [124, 136, 159, 255]),
+                ((0, 22), [90, 103, 39, 255]),
+                ((31, 22), [86, 99, 46, 255]),
+                ((16, 11), [112, 124, 139, 255]),
+            ],
+        );
+    }
+
+    #[test]
+    fn thumbhash_with_alpha_matches_reference() {
+        let image = thumbhash(&thumbhash_bytes("YJqGPQw7sFlslqhFafSE+Q6oJ1h2iHB2Rw")).unwrap();
+
+        assert_eq!(image.dimensions(), (32, 32));
+        assert_pixels(
+            &image,
+            &[
+                ((0, 0), [228, 75, 51, 0]),
+                ((31, 0), [192, 167, 23, 0]),
+                ((0, 31), [255, 33, 9, 0]),
+                ((31, 31), [255, 136, 0, 0]),
+                ((16, 16), [107, 102, 109, 255]),
+            ],
+        );
+    }
+
+    #[test]
+    fn thumbhash_base64_accepts_padding() {
+        assert_eq!(
+            PlaceholderHash::thumbhash_base64("YJqGPQw7sFlslqhFafSE+Q6oJ1h2iHB2Rw=="),
+            PlaceholderHash::thumbhash_base64("YJqGPQw7sFlslqhFafSE+Q6oJ1h2iHB2Rw"),
+        );
+        assert!(PlaceholderHash::thumbhash_base64("not base64!").is_none());
+    }
+
+    #[test]
+    fn malformed_blurhash_is_rejected() {
+        let valid = "LEHV6nWB2yk8pyo0adR*.7kCMdnj";
+
+        for hash in [
+            "",
+            "L",
+            "LEHV6",
+            &valid[..valid.len() - 1],
+            &format!("{valid}0"),
+            "LEHV6nWB2yk8pyo0adR*.7kCMdn!",
+            "LEHV6nWB2yk8pyo0adR*.7kCMdé",
+            "~EHV6nWB2yk8pyo0adR*.7kCMdnj",
+        ] {
+            assert!(blurhash(hash, 32, 32).is_none(), "{hash}");
+            assert!(PlaceholderHash::blurhash(hash).preview(1.0).is_none());
+        }
+    }
+
+    #[test]
+    fn malformed_thumbhash_is_rejected() {
+        let valid = thumbhash_bytes("YJqGPQw7sFlslqhFafSE+Q6oJ1h2iHB2Rw");
+
+        // Every prefix is missing some coefficients
+        for len in 0..valid.len() {
+            assert!(thumbhash(&valid[..len]).is_none(), "{len} bytes");
+            assert!(PlaceholderHash::ThumbHash(valid[..len].to_vec())
+                .preview(1.0)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn arbitrary_thumbhash_does_not_panic() {
+        // Headers with zero sized sides, with and without alpha
+        for header in [
+            [0, 0, 0, 0, 0],
+            [0, 0, 0x80, 0, 0],
+            [0, 0, 0, 0, 0x80],
+            [255; 5],
+        ] {
+            let mut hash = header.to_vec();
+            hash.resize(32, 0x5a);
+
+            if let Some(image) = thumbhash(&hash) {
+                assert!(image.width() >= 1 && image.height() >= 1);
+                assert!(image.width() <= PREVIEW_SIZE && image.height() <= PREVIEW_SIZE);
+            }
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use super::{decode::Fit, ShownImage};
+use floem::{
+    kurbo::{Rect, Size},
+    reactive::{create_memo, RwSignal},
+    style::Style,
+    view::{AnyView, View},
+    views::{clip, Decorators, Img},
+};
+
+/// Where the image is placed in the view when it doesn't fill it exactly, as fractions of the
+/// space left over. With [`Fit::Cover`] the overflow is cropped by the same fractions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Position {
+    pub const TOP_LEFT: Self = Self::new(0.0, 0.0);
+    pub const TOP: Self = Self::new(0.5, 0.0);
+    pub const TOP_RIGHT: Self = Self::new(1.0, 0.0);
+    pub const LEFT: Self = Self::new(0.0, 0.5);
+    pub const CENTER: Self = Self::new(0.5, 0.5);
+    pub const RIGHT: Self = Self::new(1.0, 0.5);
+    pub const BOTTOM_LEFT: Self = Self::new(0.0, 1.0);
+    pub const BOTTOM: Self = Self::new(0.5, 1.0);
+    pub const BOTTOM_RIGHT: Self = Self::new(1.0, 1.0);
+
+    #[must_use]
+    pub const fn new(x: f64, y: f64) -> Self {
+        Self { x, y }
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self::CENTER
+    }
+}
+
+/// How the image is laid out in the view
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ImageLayout {
+    /// Without it the image is stretched to the view
+    pub(crate) fit: Option<(Fit, Position)>,
+    /// Width divided by height, so the view has its size before the image arrives
+    pub(crate) aspect_ratio: Option<f32>,
+}
+
+impl ImageLayout {
+    pub(crate) fn apply(self, image: Img, buffer: RwSignal<Option<ShownImage>>) -> AnyView {
+        let view = match self.fit {
+            Some((fit, position)) => fitted(image, buffer, fit, position),
+            None => image.any(),
+        };
+
+        match self.aspect_ratio {
+            Some(ratio) => view
+                .style(move |s| s.width_full().aspect_ratio(ratio))
+                .any(),
+            None => view,
+        }
+    }
+}
+
+/// Positions the image by its size and the size of the view, and clips what overflows
+fn fitted(
+    image: Img,
+    buffer: RwSignal<Option<ShownImage>>,
+    fit: Fit,
+    position: Position,
+) -> AnyView {
+    let view_size = RwSignal::new(Size::ZERO);
+    let image_size = create_memo(move |_| buffer.with(|b| b.as_ref().map(|b| b.size)));
+
+    let image = image.style(move |s| {
+        let Some((width, height)) = image_size.get() else {
+            return s.size_full();
+        };
+
+        let image = Size::new(f64::from(width), f64::from(height));
+        let rect = fit_rect(fit, position, view_size.get(), image);
+
+        s.absolute()
+            .inset_left(rect.x0)
+            .inset_top(rect.y0)
+            .width(rect.width())
+            .height(rect.height())
+    });
+
+    clip(image)
+        .style(Style::size_full)
+        .on_resize(move |rect| view_size.set(rect.size()))
+        .any()
+}
+
+/// Rectangle of the image within the view, like CSS `object-fit` and `object-position`
+fn fit_rect(fit: Fit, position: Position, view: Size, image: Size) -> Rect {
+    if image.width <= 0.0 || image.height <= 0.0 {
+        return Rect::new(0.0, 0.0, view.width, view.height);
+    }
+
+    let contain = (view.width / image.width).min(view.height / image.height);
+    let cover = (view.width / image.width).max(view.height / image.height);
+
+    let scale = match fit {
+        Fit::Fill => return Rect::new(0.0, 0.0, view.width, view.height),
+        Fit::Contain => contain,
+        Fit::Cover => cover,
+        Fit::None => 1.0,
+        Fit::ScaleDown => contain.min(1.0),
+    };
+
+    let width = image.width * scale;
+    let height = image.height * scale;
+    let x = (view.width - width) * position.x;
+    let y = (view.height - height) * position.y;
+
+    Rect::new(x, y, x + width, y + height)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIEW: Size = Size {
+        width: 200.0,
+        height: 100.0,
+    };
+
+    fn rect(fit: Fit, position: Position, image: (f64, f64)) -> (f64, f64, f64, f64) {
+        let rect = fit_rect(fit, position, VIEW, Size::new(image.0, image.1));
+        (rect.x0, rect.y0, rect.x1, rect.y1)
+    }
+
+    #[test]
+    fn fill_stretches_to_view() {
+        for position in [Position::TOP_LEFT, Position::CENTER, Position::BOTTOM_RIGHT] {
+            assert_eq!(
+                rect(Fit::Fill, position, (50.0, 400.0)),
+                (0.0, 0.0, 200.0, 100.0)
+            );
+        }
+    }
+
+    #[test]
+    fn contain_fits_inside_view() {
+        let image = (400.0, 400.0);
+        assert_eq!(
+            rect(Fit::Contain, Position::CENTER, image),
+            (50.0, 0.0, 150.0, 100.0)
+        );
+        assert_eq!(
+            rect(Fit::Contain, Position::LEFT, image),
+            (0.0, 0.0, 100.0, 100.0)
+        );
+        assert_eq!(
+            rect(Fit::Contain, Position::RIGHT, image),
+            (100.0, 0.0, 200.0, 100.0)
+        );
+        assert_eq!(
+            rect(Fit::Contain, Position::new(0.25, 0.0), image),
+            (25.0, 0.0, 125.0, 100.0)
+        );
+
+        // Smaller images are scaled up
+        assert_eq!(
+            rect(Fit::Contain, Position::TOP, (20.0, 5.0)),
+            (0.0, 0.0, 200.0, 50.0)
+        );
+        assert_eq!(
+            rect(Fit::Contain, Position::BOTTOM, (20.0, 5.0)),
+            (0.0, 50.0, 200.0, 100.0)
+        );
+    }
+
+    #[test]
+    fn cover_fills_view_and_crops() {
+        let image = (100.0, 100.0);
+        assert_eq!(
+            rect(Fit::Cover, Position::CENTER, image),
+            (0.0, -50.0, 200.0, 150.0)
+        );
+        assert_eq!(
+            rect(Fit::Cover, Position::TOP, image),
+            (0.0, 0.0, 200.0, 200.0)
+        );
+        assert_eq!(
+            rect(Fit::Cover, Position::BOTTOM, image),
+            (0.0, -100.0, 200.0, 100.0)
+        );
+
+        let wide = (800.0, 100.0);
+        assert_eq!(
+            rect(Fit::Cover, Position::LEFT, wide),
+            (0.0, 0.0, 800.0, 100.0)
+        );
+        assert_eq!(
+            rect(Fit::Cover, Position::RIGHT, wide),
+            (-600.0, 0.0, 200.0, 100.0)
+        );
+    }
+
+    #[test]
+    fn none_keeps_image_size() {
+        let small = (50.0, 20.0);
+        assert_eq!(
+            rect(Fit::None, Position::CENTER, small),
+            (75.0, 40.0, 125.0, 60.0)
+        );
+        assert_eq!(
+            rect(Fit::None, Position::TOP_LEFT, small),
+            (0.0, 0.0, 50.0, 20.0)
+        );
+        assert_eq!(
+            rect(Fit::None, Position::BOTTOM_RIGHT, small),
+            (150.0, 80.0, 200.0, 100.0)
+        );
+
+        let large = (400.0, 400.0);
+        assert_eq!(
+            rect(Fit::None, Position::CENTER, large),
+            (-100.0, -150.0, 300.0, 250.0)
+        );
+        assert_eq!(
+            rect(Fit::None, Position::TOP_LEFT, large),
+            (0.0, 0.0, 400.0, 400.0)
+        );
+    }
+
+    #[test]
+    fn scale_down_only_shrinks() {
+        let small = (50.0, 20.0);
+        assert_eq!(
+            rect(Fit::ScaleDown, Position::CENTER, small),
+            (75.0, 40.0, 125.0, 60.0)
+        );
+        assert_eq!(
+            rect(Fit::ScaleDown, Position::BOTTOM_LEFT, small),
+            (0.0, 80.0, 50.0, 100.0)
+        );
+
+        let large = (400.0, 400.0);
+        assert_eq!(
+            rect(Fit::ScaleDown, Position::CENTER, large),
+            (50.0, 0.0, 150.0, 100.0)
+        );
+        assert_eq!(
+            rect(Fit::ScaleDown, Position::TOP_RIGHT, large),
+            (100.0, 0.0, 200.0, 100.0)
+        );
+    }
+
+    #[test]
+    fn empty_image_fills_view() {
+        for fit in [Fit::Contain, Fit::Cover, Fit::None, Fit::ScaleDown] {
+            assert_eq!(
+                rect(fit, Position::CENTER, (0.0, 100.0)),
+                (0.0, 0.0, 200.0, 100.0)
+            );
+        }
+    }
+}
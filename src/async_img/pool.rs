@@ -0,0 +1,270 @@
+use std::{
+    collections::VecDeque,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, OnceLock,
+    },
+    task::{Context, Wake, Waker},
+};
+
+use super::executor::{BoxFuture, Spawner, TaskHandle};
+
+/// Number of worker threads of [`ThreadPool::global`] unless changed
+const DEFAULT_THREADS: usize = 8;
+
+/// Worker threads that run fetches for the `thread` feature, without an async runtime.
+/// Workers only poll the fetches, so a few of them are enough for any number of images.
+///
+/// Fetches are the same futures the async runtimes run, rather than blocking requests on a
+/// `reqwest::blocking::Client`, so that progress, the [`Scheduler`](super::scheduler::Scheduler)
+/// and cancellation work alike. A blocking client runs a tokio runtime of its own anyway. Here
+/// fetches are wrapped in `async_compat::Compat`, so requests share one `reqwest::Client` on
+/// its single-threaded tokio runtime, and decoding happens on the blocking pool.
+#[derive(Clone)]
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    available: Condvar,
+}
+
+struct State {
+    /// Tasks that were woken up
+    queue: VecDeque<Arc<Task>>,
+    /// Workers exit when there are more alive than wanted
+    threads: usize,
+    alive: usize,
+}
+
+struct Task {
+    /// Taken when done or cancelled
+    future: Mutex<Option<BoxFuture>>,
+    /// Set while in the queue, so that several wake-ups queue it once
+    queued: AtomicBool,
+    cancelled: AtomicBool,
+    shared: Arc<Shared>,
+}
+
+impl ThreadPool {
+    #[must_use]
+    pub fn new(threads: usize) -> Self {
+        let pool = Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    queue: VecDeque::new(),
+                    threads: 0,
+                    alive: 0,
+                }),
+                available: Condvar::new(),
+            }),
+        };
+
+        pool.set_threads(threads);
+        pool
+    }
+
+    /// Pool used by default when `thread` is the only runtime feature
+    #[must_use]
+    pub fn global() -> Self {
+        static GLOBAL: OnceLock<ThreadPool> = OnceLock::new();
+        GLOBAL
+            .get_or_init(|| ThreadPool::new(DEFAULT_THREADS))
+            .clone()
+    }
+
+    /// Changes the number of worker threads. Extra workers exit after their current poll.
+    pub fn set_threads(&self, threads: usize) {
+        let threads = threads.max(1);
+
+        let mut state = self.shared.state.lock().unwrap();
+        state.threads = threads;
+
+        while state.alive < threads {
+            state.alive += 1;
+
+            let shared = Arc::clone(&self.shared);
+            std::thread::spawn(move || shared.work());
+        }
+
+        self.shared.available.notify_all();
+    }
+}
+
+impl Spawner for ThreadPool {
+    fn spawn(&self, future: BoxFuture) -> TaskHandle {
+        let task = Arc::new(Task {
+            future: Mutex::new(Some(Box::pin(async_compat::Compat::new(future)))),
+            queued: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            shared: Arc::clone(&self.shared),
+        });
+
+        Arc::clone(&task).wake();
+
+        TaskHandle::new(move || task.cancel())
+    }
+}
+
+impl Shared {
+    fn work(&self) {
+        loop {
+            let task = {
+                let mut state = self.state.lock().unwrap();
+
+                loop {
+                    if state.alive > state.threads {
+                        state.alive -= 1;
+                        return;
+                    }
+
+                    if let Some(task) = state.queue.pop_front() {
+                        break task;
+                    }
+
+                    state = self.available.wait(state).unwrap();
+                }
+            };
+
+            task.poll();
+        }
+    }
+
+    fn push(&self, task: Arc<Task>) {
+        self.state.lock().unwrap().queue.push_back(task);
+        self.available.notify_one();
+    }
+}
+
+impl Task {
+    fn poll(self: Arc<Self>) {
+        self.queued.store(false, Ordering::Release);
+
+        let waker = Waker::from(Arc::clone(&self));
+        let mut cx = Context::from_waker(&waker);
+
+        let mut future = self.future.lock().unwrap();
+        let Some(fut) = future.as_mut() else {
+            return;
+        };
+
+        // Panic is already reported by the hook, and the worker stays alive
+        let done = std::panic::catch_unwind(AssertUnwindSafe(|| fut.as_mut().poll(&mut cx)))
+            .map_or(true, |poll| poll.is_ready());
+
+        if done || self.cancelled.load(Ordering::Acquire) {
+            *future = None;
+        }
+    }
+
+    /// Drops the future, or leaves it to the worker polling it. A task may cancel itself
+    /// while being polled.
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+
+        if let Ok(mut future) = self.future.try_lock() {
+            *future = None;
+        }
+    }
+}
+
+impl Wake for Task {
+    fn wake(self: Arc<Self>) {
+        if !self.queued.swap(true, Ordering::AcqRel) && !self.cancelled.load(Ordering::Acquire) {
+            let shared = Arc::clone(&self.shared);
+            shared.push(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    /// Sends when the future owning it is dropped
+    struct Dropped(mpsc::Sender<()>);
+
+    impl Drop for Dropped {
+        fn drop(&mut self) {
+            let _ = self.0.send(());
+        }
+    }
+
+    fn alive(pool: &ThreadPool) -> usize {
+        pool.shared.state.lock().unwrap().alive
+    }
+
+    fn wait_until(done: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !done() {
+            assert!(started.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn spawned_futures_run_after_wake_ups() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+
+        let handles: Vec<_> = (0..10)
+            .map(|i| {
+                let sender = sender.clone();
+                pool.spawn(Box::pin(async move {
+                    blocking::unblock(|| thread::sleep(Duration::from_millis(10))).await;
+                    sender.send(i).unwrap();
+                }))
+            })
+            .collect();
+
+        let mut done: Vec<_> = (0..10)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(10)).unwrap())
+            .collect();
+        done.sort_unstable();
+        assert_eq!(done, (0..10).collect::<Vec<_>>());
+        drop(handles);
+    }
+
+    #[test]
+    fn dropping_the_handle_cancels() {
+        let pool = ThreadPool::new(1);
+        let (sender, dropped) = mpsc::channel();
+
+        let handle = pool.spawn(Box::pin(async move {
+            let _dropped = Dropped(sender);
+            std::future::pending::<()>().await;
+        }));
+
+        // Polled once, and left waiting
+        thread::sleep(Duration::from_millis(50));
+        assert!(dropped.try_recv().is_err());
+
+        drop(handle);
+        dropped.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn workers_exit_when_shrunk() {
+        let pool = ThreadPool::new(4);
+        assert_eq!(alive(&pool), 4);
+
+        pool.set_threads(0);
+        wait_until(|| alive(&pool) == 1);
+
+        pool.set_threads(3);
+        assert_eq!(alive(&pool), 3);
+
+        // Still runs fetches
+        let (sender, receiver) = mpsc::channel();
+        let _handle = pool.spawn(Box::pin(async move { sender.send(()).unwrap() }));
+        receiver.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+}
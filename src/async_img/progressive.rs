@@ -0,0 +1,248 @@
+use bytes::{Bytes, BytesMut};
+use image::{DynamicImage, Rgba, RgbaImage};
+use png::{ColorType, Decoder, Transformations};
+
+use super::{
+    decode::{decode_still, resize, rgba},
+    FetchOptions,
+};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// First pixel and spacing of the pixels of each Adam7 pass, as `(x, y, dx, dy)`
+const ADAM7: [(u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+/// Decodes an image that is still downloading. PNG shows the rows received so far, or for
+/// interlaced PNG the passes received so far at lower resolution. Truncated JPEG gets an end
+/// of image marker so that the decoder renders the scans received so far. Other formats are
+/// decoded as they are, which fails for most of them.
+pub(crate) fn decode_partial(data: &[u8], options: FetchOptions) -> Option<DynamicImage> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return decode_still(&jpeg_data(data), options).ok();
+    }
+
+    let image = DynamicImage::ImageRgba8(partial_png(data)?);
+    Some(rgba(resize(image, options)))
+}
+
+fn jpeg_data(data: &[u8]) -> Bytes {
+    let mut bytes = BytesMut::from(data);
+
+    if data.starts_with(b"\xff\xd8") && !data.ends_with(b"\xff\xd9") {
+        bytes.extend_from_slice(b"\xff\xd9");
+    }
+
+    bytes.freeze()
+}
+
+/// Rows are read until the data runs out. Pixels of an interlaced pass fill the block up to
+/// the next pixel of the pass, and later passes refine them. Pixels not received yet are
+/// transparent.
+#[allow(clippy::cast_possible_truncation)]
+fn partial_png(data: &[u8]) -> Option<RgbaImage> {
+    let mut decoder = Decoder::new(data);
+    decoder.set_transformations(Transformations::normalize_to_color8());
+
+    let mut reader = decoder.read_info().ok()?;
+    let (width, height) = reader.info().size();
+    let interlaced = reader.info().interlaced;
+    let (color, _) = reader.output_color_type();
+
+    let mut image = RgbaImage::new(width, height);
+    let mut rows = 0;
+    let mut passes = adam7_rows(width, height);
+
+    while let Ok(Some(row)) = reader.next_interlaced_row() {
+        let pixels = row
+            .data()
+            .chunks_exact(color.samples())
+            .map(|pixel| to_rgba(color, pixel));
+
+        if interlaced {
+            let ((x0, y0, dx, dy), line) = passes.next()?;
+            let top = y0 + line * dy;
+
+            for (i, pixel) in pixels.enumerate() {
+                let left = x0 + i as u32 * dx;
+                fill(&mut image, (left, top), (dx - x0, dy - y0), pixel?);
+            }
+        } else {
+            for (x, pixel) in pixels.enumerate() {
+                image.put_pixel(x as u32, rows, pixel?);
+            }
+        }
+
+        rows += 1;
+    }
+
+    (rows > 0).then_some(image)
+}
+
+/// Pass and line within it of each row of an interlaced PNG, in the order the decoder
+/// returns them. Passes without pixels are skipped, as the decoder does.
+fn adam7_rows(width: u32, height: u32) -> impl Iterator<Item = ((u32, u32, u32, u32), u32)> {
+    ADAM7
+        .into_iter()
+        .filter(move |&(x0, y0, _, _)| width > x0 && height > y0)
+        .flat_map(move |pass @ (_, y0, _, dy)| {
+            (0..(height - y0).div_ceil(dy)).map(move |line| (pass, line))
+        })
+}
+
+fn fill(image: &mut RgbaImage, (x, y): (u32, u32), (w, h): (u32, u32), pixel: Rgba<u8>) {
+    for y in y..(y + h).min(image.height()) {
+        for x in x..(x + w).min(image.width()) {
+            image.put_pixel(x, y, pixel);
+        }
+    }
+}
+
+/// Palettes and bit depths are already expanded to 8-bit samples
+fn to_rgba(color: ColorType, pixel: &[u8]) -> Option<Rgba<u8>> {
+    match (color, pixel) {
+        (ColorType::Grayscale, &[l]) => Some(Rgba([l, l, l, 255])),
+        (ColorType::GrayscaleAlpha, &[l, a]) => Some(Rgba([l, l, l, a])),
+        (ColorType::Rgb, &[r, g, b]) => Some(Rgba([r, g, b, 255])),
+        (ColorType::Rgba, &[r, g, b, a]) => Some(Rgba([r, g, b, a])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 16;
+
+    /// Bytes before the first row in [`png`]: signature, `IHDR`, `IDAT` header, zlib header and
+    /// the header of the stored block
+    const ROWS_START: usize = 8 + 25 + 8 + 2 + 5;
+
+    fn color(x: u32, y: u32) -> Rgba<u8> {
+        Rgba([(x * 16) as u8, (y * 16) as u8, 128, 255])
+    }
+
+    /// Uncompressed RGB PNG, so that truncating it at a row boundary is exact
+    fn png(interlaced: bool) -> Vec<u8> {
+        let passes: &[_] = if interlaced { &ADAM7 } else { &[(0, 0, 1, 1)] };
+
+        let mut raw = Vec::new();
+        for &(x0, y0, dx, dy) in passes {
+            for y in (y0..SIZE).step_by(dy as usize) {
+                raw.push(0);
+                for x in (x0..SIZE).step_by(dx as usize) {
+                    raw.extend_from_slice(&color(x, y).0[..3]);
+                }
+            }
+        }
+
+        let mut ihdr = Vec::new();
+        ihdr.extend(SIZE.to_be_bytes());
+        ihdr.extend(SIZE.to_be_bytes());
+        ihdr.extend([8, 2, 0, 0, u8::from(interlaced)]);
+
+        let mut png = PNG_SIGNATURE.to_vec();
+        chunk(&mut png, b"IHDR", &ihdr);
+        chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+        chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+        png.extend((data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend(kind);
+        png.extend(data);
+        let crc = crc32(&png[start..]);
+        png.extend(crc.to_be_bytes());
+    }
+
+    /// Single stored deflate block
+    fn zlib_stored(data: &[u8]) -> Vec<u8> {
+        let len = u16::try_from(data.len()).unwrap();
+
+        let mut zlib = vec![0x78, 0x01, 1];
+        zlib.extend(len.to_le_bytes());
+        zlib.extend((!len).to_le_bytes());
+        zlib.extend(data);
+
+        let (mut a, mut b) = (1_u32, 0_u32);
+        for &byte in data {
+            a = (a + u32::from(byte)) % 65521;
+            b = (b + a) % 65521;
+        }
+        zlib.extend(((b << 16) | a).to_be_bytes());
+        zlib
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = !0_u32;
+        for &byte in bytes {
+            crc ^= u32::from(byte);
+            for _ in 0..8 {
+                crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+            }
+        }
+        !crc
+    }
+
+    #[test]
+    fn complete_png_is_decoded_exactly() {
+        for interlaced in [false, true] {
+            let image = partial_png(&png(interlaced)).unwrap();
+
+            assert_eq!(image.dimensions(), (SIZE, SIZE));
+            for (x, y, pixel) in image.enumerate_pixels() {
+                assert_eq!(*pixel, color(x, y), "interlaced: {interlaced}");
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_png_shows_rows_received() {
+        let row = 1 + 3 * SIZE as usize;
+        let data = png(false);
+        let image = partial_png(&data[..ROWS_START + 4 * row + row / 2]).unwrap();
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = if y < 4 { color(x, y) } else { Rgba([0; 4]) };
+            assert_eq!(*pixel, expected);
+        }
+    }
+
+    #[test]
+    fn truncated_interlaced_png_fills_blocks_of_first_pass() {
+        // First pass has two rows of two pixels
+        let data = png(true);
+        let image = partial_png(&data[..ROWS_START + 2 * (1 + 2 * 3)]).unwrap();
+
+        for (x, y, pixel) in image.enumerate_pixels() {
+            assert_eq!(*pixel, color(x / 8 * 8, y / 8 * 8));
+        }
+    }
+
+    #[test]
+    fn png_without_rows_is_not_decoded() {
+        let data = png(true);
+        assert!(partial_png(&data[..ROWS_START]).is_none());
+        assert!(partial_png(&data[..20]).is_none());
+    }
+
+    #[test]
+    fn truncated_jpeg_gets_end_marker() {
+        assert_eq!(
+            &jpeg_data(b"\xff\xd8\xff\xe0")[..],
+            b"\xff\xd8\xff\xe0\xff\xd9"
+        );
+        assert_eq!(&jpeg_data(b"\xff\xd8\xff\xd9")[..], b"\xff\xd8\xff\xd9");
+        assert_eq!(&jpeg_data(b"GIF89a")[..], b"GIF89a");
+    }
+}
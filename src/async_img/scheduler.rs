@@ -0,0 +1,491 @@
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    task::{Context, Poll, Waker},
+};
+
+/// Order in which queued downloads start. Downloads with the same priority start in the
+/// order they were queued.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Prefetches and images that are off-screen
+    Low,
+    #[default]
+    Normal,
+    /// Images that are visible
+    High,
+}
+
+impl Priority {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Priority::Low,
+            1 => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+}
+
+/// Priority of a view's download that can be changed while it's queued, for example when
+/// the view scrolls into view
+#[derive(Clone, Debug)]
+pub struct SharedPriority(Arc<AtomicU8>);
+
+impl SharedPriority {
+    #[must_use]
+    pub fn new(priority: Priority) -> Self {
+        Self(Arc::new(AtomicU8::new(priority as u8)))
+    }
+
+    #[must_use]
+    pub fn get(&self) -> Priority {
+        Priority::from_u8(self.0.load(Ordering::Relaxed))
+    }
+
+    pub fn set(&self, priority: Priority) {
+        self.0.store(priority as u8, Ordering::Relaxed);
+    }
+}
+
+impl Default for SharedPriority {
+    fn default() -> Self {
+        Self::new(Priority::default())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedulerConfig {
+    /// Maximum number of downloads running at once
+    pub max_concurrent: Option<usize>,
+    /// Maximum number of downloads from the same host running at once
+    pub max_per_host: Option<usize>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: Some(16),
+            max_per_host: Some(6),
+        }
+    }
+}
+
+/// Limits how many images are downloaded at once. Downloads over the limits wait in a queue
+/// and start by priority as running ones finish. Reading from the cache doesn't count.
+///
+/// Views use the scheduler provided with floems `provide_context`, or [`Scheduler::global`].
+/// [`AsyncCache`](super::cache::AsyncCache) uses the one in its config.
+#[derive(Clone)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    config: SchedulerConfig,
+    running: usize,
+    per_host: HashMap<String, usize>,
+    queue: Vec<Waiting>,
+    next_seq: u64,
+}
+
+struct Waiting {
+    seq: u64,
+    host: String,
+    /// Read whenever a slot frees up, so changes apply while queued
+    priority: Box<dyn Fn() -> Priority + Send>,
+    waker: Option<Waker>,
+    /// Counted as running, but the waiting task hasn't taken the permit yet
+    granted: bool,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(SchedulerConfig::default())
+    }
+}
+
+impl Scheduler {
+    #[must_use]
+    pub fn new(config: SchedulerConfig) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                config,
+                running: 0,
+                per_host: HashMap::new(),
+                queue: Vec::new(),
+                next_seq: 0,
+            })),
+        }
+    }
+
+    /// Scheduler shared by everything that isn't given another one
+    #[must_use]
+    pub fn global() -> Self {
+        static GLOBAL: OnceLock<Scheduler> = OnceLock::new();
+        GLOBAL.get_or_init(Scheduler::default).clone()
+    }
+
+    /// Changes the limits. Queued downloads start right away if the limits were raised.
+    pub fn set_config(&self, config: SchedulerConfig) {
+        let mut state = self.state.lock().unwrap();
+        state.config = config;
+        state.dispatch();
+    }
+
+    /// Number of downloads running
+    #[must_use]
+    pub fn running(&self) -> usize {
+        self.state.lock().unwrap().running
+    }
+
+    /// Number of downloads waiting in the queue
+    #[must_use]
+    pub fn queued(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.queue.iter().filter(|w| !w.granted).count()
+    }
+
+    /// Waits for a free slot for a download from `host`
+    pub(crate) fn acquire(
+        &self,
+        host: &str,
+        priority: impl Fn() -> Priority + Send + 'static,
+    ) -> Acquire {
+        let mut state = self.state.lock().unwrap();
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+
+        state.queue.push(Waiting {
+            seq,
+            host: host.to_string(),
+            priority: Box::new(priority),
+            waker: None,
+            granted: false,
+        });
+        state.dispatch();
+
+        Acquire {
+            scheduler: self.clone(),
+            seq,
+            done: false,
+        }
+    }
+}
+
+impl State {
+    fn has_room(&self, host: &str) -> bool {
+        let SchedulerConfig {
+            max_concurrent,
+            max_per_host,
+        } = self.config;
+
+        let running_host = self.per_host.get(host).copied().unwrap_or_default();
+
+        let over_limit = max_concurrent.is_some_and(|max| self.running >= max)
+            || max_per_host.is_some_and(|max| running_host >= max);
+
+        !over_limit
+    }
+
+    /// Grants free slots to the waiting downloads with highest priority
+    fn dispatch(&mut self) {
+        loop {
+            let next = self
+                .queue
+                .iter()
+                .enumerate()
+                .filter(|(_, waiting)| !waiting.granted && self.has_room(&waiting.host))
+                .max_by_key(|(_, waiting)| ((waiting.priority)(), Reverse(waiting.seq)))
+                .map(|(i, _)| i);
+
+            let Some(i) = next else {
+                break;
+            };
+
+            self.running += 1;
+            *self.per_host.entry(self.queue[i].host.clone()).or_default() += 1;
+
+            let waiting = &mut self.queue[i];
+            waiting.granted = true;
+            if let Some(waker) = waiting.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    fn release(&mut self, host: &str) {
+        self.running -= 1;
+
+        if let Some(count) = self.per_host.get_mut(host) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_host.remove(host);
+            }
+        }
+
+        self.dispatch();
+    }
+}
+
+/// Future of a queued download that resolves when it may start. Dropping it leaves the queue.
+pub(crate) struct Acquire {
+    scheduler: Scheduler,
+    seq: u64,
+    done: bool,
+}
+
+impl Future for Acquire {
+    type Output = Permit;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Permit> {
+        let mut state = self.scheduler.state.lock().unwrap();
+
+        let Some(i) = state.queue.iter().position(|w| w.seq == self.seq) else {
+            unreachable!("queued download is only removed by its future");
+        };
+
+        if !state.queue[i].granted {
+            state.queue[i].waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let host = state.queue.swap_remove(i).host;
+        drop(state);
+
+        self.done = true;
+        Poll::Ready(Permit {
+            scheduler: self.scheduler.clone(),
+            host,
+        })
+    }
+}
+
+impl Drop for Acquire {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut state = self.scheduler.state.lock().unwrap();
+
+        if let Some(i) = state.queue.iter().position(|w| w.seq == self.seq) {
+            let waiting = state.queue.swap_remove(i);
+            // Slot was given to this download just before it was cancelled
+            if waiting.granted {
+                state.release(&waiting.host);
+            }
+        }
+    }
+}
+
+/// Slot of a running download, freed when dropped
+pub(crate) struct Permit {
+    scheduler: Scheduler,
+    host: String,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.state.lock().unwrap().release(&self.host);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::AtomicBool, task::Wake};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn poll(acquire: &mut Acquire) -> Option<Permit> {
+        poll_with(acquire, &Arc::default())
+    }
+
+    fn poll_with(acquire: &mut Acquire, flag: &Arc<Flag>) -> Option<Permit> {
+        let waker = Waker::from(Arc::clone(flag));
+        match Pin::new(acquire).poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(permit) => Some(permit),
+            Poll::Pending => None,
+        }
+    }
+
+    fn limited(max_concurrent: Option<usize>, max_per_host: Option<usize>) -> Scheduler {
+        Scheduler::new(SchedulerConfig {
+            max_concurrent,
+            max_per_host,
+        })
+    }
+
+    fn acquire(scheduler: &Scheduler, priority: Priority) -> Acquire {
+        scheduler.acquire("example.com", move || priority)
+    }
+
+    /// Releases `permit` and returns the download of `queue` that got the slot
+    fn next(permit: Permit, queue: &mut Vec<(&'static str, Acquire)>) -> (&'static str, Permit) {
+        drop(permit);
+
+        let mut granted = Vec::new();
+        queue.retain_mut(|(name, acquire)| match poll(acquire) {
+            Some(permit) => {
+                granted.push((*name, permit));
+                false
+            }
+            None => true,
+        });
+
+        assert_eq!(granted.len(), 1);
+        granted.pop().unwrap()
+    }
+
+    #[test]
+    fn starts_by_priority() {
+        let scheduler = limited(Some(1), None);
+        let permit = poll(&mut acquire(&scheduler, Priority::Normal)).unwrap();
+
+        let mut queue = vec![
+            ("low", acquire(&scheduler, Priority::Low)),
+            ("high", acquire(&scheduler, Priority::High)),
+            ("normal", acquire(&scheduler, Priority::Normal)),
+        ];
+        for (_, acquire) in &mut queue {
+            assert!(poll(acquire).is_none());
+        }
+        assert_eq!(scheduler.queued(), 3);
+
+        let (name, permit) = next(permit, &mut queue);
+        assert_eq!(name, "high");
+        let (name, permit) = next(permit, &mut queue);
+        assert_eq!(name, "normal");
+        let (name, permit) = next(permit, &mut queue);
+        assert_eq!(name, "low");
+
+        drop(permit);
+        assert_eq!(scheduler.running(), 0);
+    }
+
+    #[test]
+    fn same_priority_starts_in_order() {
+        let scheduler = limited(Some(1), None);
+        let mut permit = poll(&mut acquire(&scheduler, Priority::Normal)).unwrap();
+
+        let mut queue = vec![
+            ("first", acquire(&scheduler, Priority::Normal)),
+            ("second", acquire(&scheduler, Priority::Normal)),
+            ("third", acquire(&scheduler, Priority::Normal)),
+        ];
+
+        for expected in ["first", "second", "third"] {
+            let (name, next_permit) = next(permit, &mut queue);
+            assert_eq!(name, expected);
+            permit = next_permit;
+        }
+    }
+
+    #[test]
+    fn limits_downloads_per_host() {
+        let scheduler = limited(None, Some(1));
+
+        let first = poll(&mut scheduler.acquire("a.com", || Priority::Normal)).unwrap();
+        let mut second = scheduler.acquire("a.com", || Priority::High);
+        let other = poll(&mut scheduler.acquire("b.com", || Priority::Normal));
+
+        assert!(poll(&mut second).is_none());
+        assert!(other.is_some());
+        assert_eq!(scheduler.running(), 2);
+        assert_eq!(scheduler.queued(), 1);
+
+        drop(first);
+        assert!(poll(&mut second).is_some());
+        assert_eq!(scheduler.queued(), 0);
+    }
+
+    #[test]
+    fn priority_change_applies_while_queued() {
+        let scheduler = limited(Some(1), None);
+        let permit = poll(&mut acquire(&scheduler, Priority::Normal)).unwrap();
+
+        let visible = SharedPriority::new(Priority::Low);
+        let priority = visible.clone();
+
+        let mut queue = vec![
+            ("normal", acquire(&scheduler, Priority::Normal)),
+            (
+                "visible",
+                scheduler.acquire("example.com", move || priority.get()),
+            ),
+        ];
+        visible.set(Priority::High);
+
+        let (name, _) = next(permit, &mut queue);
+        assert_eq!(name, "visible");
+    }
+
+    #[test]
+    fn granted_download_is_woken() {
+        let scheduler = limited(Some(1), None);
+        let permit = poll(&mut acquire(&scheduler, Priority::Normal)).unwrap();
+
+        let flag = Arc::default();
+        let mut waiting = acquire(&scheduler, Priority::Normal);
+        assert!(poll_with(&mut waiting, &flag).is_none());
+        assert!(!flag.0.load(Ordering::SeqCst));
+
+        drop(permit);
+        assert!(flag.0.load(Ordering::SeqCst));
+        assert!(poll(&mut waiting).is_some());
+    }
+
+    #[test]
+    fn dropped_acquire_frees_its_slot() {
+        let scheduler = limited(Some(1), None);
+
+        // Granted right away, but dropped before taking the permit
+        let granted = acquire(&scheduler, Priority::Normal);
+        let mut waiting = acquire(&scheduler, Priority::Normal);
+        assert_eq!(scheduler.running(), 1);
+        assert!(poll(&mut waiting).is_none());
+
+        drop(granted);
+        assert_eq!(scheduler.running(), 1);
+        let permit = poll(&mut waiting).unwrap();
+
+        // Leaves the queue without taking a slot
+        let queued = acquire(&scheduler, Priority::Normal);
+        assert_eq!(scheduler.queued(), 1);
+        drop(queued);
+        assert_eq!(scheduler.queued(), 0);
+
+        drop(permit);
+        assert_eq!(scheduler.running(), 0);
+    }
+
+    #[test]
+    fn raised_limit_starts_queued_downloads() {
+        let scheduler = limited(Some(1), None);
+        let _permit = poll(&mut acquire(&scheduler, Priority::Normal)).unwrap();
+        let mut waiting = acquire(&scheduler, Priority::Normal);
+        assert!(poll(&mut waiting).is_none());
+
+        scheduler.set_config(SchedulerConfig {
+            max_concurrent: Some(2),
+            max_per_host: None,
+        });
+        let _started = poll(&mut waiting).unwrap();
+        assert_eq!(scheduler.running(), 2);
+    }
+}
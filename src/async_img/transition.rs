@@ -0,0 +1,154 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use floem::{action::exec_after, reactive::RwSignal};
+use image::{DynamicImage, RgbaImage};
+
+use super::ShownImage;
+
+/// Time between frames of a fade
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+
+/// Longest side in pixels of the blended frames. They are stretched to the size of the
+/// image, which isn't noticeable while it fades.
+const FRAME_SIZE: u32 = 256;
+
+/// Fades from the image shown to a new one. Each frame is blended when its timer fires and
+/// put in the buffer.
+pub(crate) struct Fade {
+    duration: Duration,
+    buffer: RwSignal<Option<ShownImage>>,
+    /// Incremented to stop the running fade
+    generation: Rc<Cell<u64>>,
+}
+
+/// Fade in progress
+struct Frames {
+    from: RgbaImage,
+    to: RgbaImage,
+    /// Shown as the last frame rather than a blend
+    image: ShownImage,
+    count: u32,
+}
+
+impl Fade {
+    pub(crate) fn new(duration: Duration, buffer: RwSignal<Option<ShownImage>>) -> Self {
+        Self {
+            duration,
+            buffer,
+            generation: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Stops the previous fade, which ends where the new one starts from
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn start(&self, from: Option<ShownImage>, to: Rc<DynamicImage>) {
+        self.generation.set(self.generation.get() + 1);
+
+        let (from, to_pixels) = pixels(from.as_ref().map(|from| &*from.image), &to);
+        let frames = Frames {
+            from,
+            to: to_pixels,
+            image: ShownImage::new(to),
+            count: (self.duration.as_millis() / FRAME_INTERVAL.as_millis()).max(1) as u32,
+        };
+
+        show(self.buffer, Rc::clone(&self.generation), frames, 1);
+    }
+}
+
+/// Shows the frame and schedules the next one. Timers of a fade that was stopped, or whose
+/// view is gone, do nothing.
+#[allow(clippy::cast_precision_loss)]
+fn show(
+    buffer: RwSignal<Option<ShownImage>>,
+    generation: Rc<Cell<u64>>,
+    frames: Frames,
+    index: u32,
+) {
+    if index >= frames.count {
+        buffer.set(Some(frames.image));
+        return;
+    }
+
+    let t = index as f32 / frames.count as f32;
+    if let Some(frame) = blend(&frames.from, &frames.to, t) {
+        buffer.set(Some(ShownImage {
+            image: Rc::new(frame),
+            size: frames.image.size,
+        }));
+    }
+
+    let current = generation.get();
+    exec_after(FRAME_INTERVAL, move |_| {
+        if generation.get() == current {
+            show(buffer, generation, frames, index + 1);
+        }
+    });
+}
+
+impl Drop for Fade {
+    fn drop(&mut self) {
+        self.generation.set(self.generation.get() + 1);
+    }
+}
+
+/// Both images at the size of the frames. Without an image shown before, the new one fades
+/// in from transparent.
+fn pixels(from: Option<&DynamicImage>, to: &DynamicImage) -> (RgbaImage, RgbaImage) {
+    let to = if to.width() > FRAME_SIZE || to.height() > FRAME_SIZE {
+        to.thumbnail(FRAME_SIZE, FRAME_SIZE).into_rgba8()
+    } else {
+        to.to_rgba8()
+    };
+    let (width, height) = to.dimensions();
+
+    let from = from.map_or_else(
+        || RgbaImage::new(width, height),
+        |from| from.thumbnail_exact(width, height).into_rgba8(),
+    );
+
+    (from, to)
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn blend(from: &RgbaImage, to: &RgbaImage, t: f32) -> Option<DynamicImage> {
+    let data = from
+        .as_raw()
+        .iter()
+        .zip(to.as_raw())
+        .map(|(&a, &b)| (f32::from(a) + (f32::from(b) - f32::from(a)) * t).round() as u8)
+        .collect();
+
+    RgbaImage::from_raw(to.width(), to.height(), data).map(DynamicImage::ImageRgba8)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba;
+
+    use super::*;
+
+    #[test]
+    fn frames_are_blended_at_bounded_size() {
+        let from = DynamicImage::ImageRgba8(RgbaImage::from_pixel(300, 300, Rgba([0; 4])));
+        let to = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1024, 512, Rgba([200; 4])));
+
+        let (from, to) = pixels(Some(&from), &to);
+        assert_eq!(from.dimensions(), (256, 128));
+        assert_eq!(to.dimensions(), (256, 128));
+
+        let frame = blend(&from, &to, 0.25).unwrap().into_rgba8();
+        assert_eq!(frame.dimensions(), (256, 128));
+        assert_eq!(*frame.get_pixel(10, 10), Rgba([50; 4]));
+    }
+
+    #[test]
+    fn small_image_fades_in_from_transparent() {
+        let to = DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, Rgba([100; 4])));
+
+        let (from, to) = pixels(None, &to);
+        assert_eq!(from.dimensions(), (40, 20));
+        assert_eq!(*from.get_pixel(0, 0), Rgba([0; 4]));
+        assert_eq!(to.dimensions(), (40, 20));
+    }
+}